@@ -0,0 +1,143 @@
+//! Projectile spawn throughput with 200 towers shooting at one target.
+//!
+//! `real fire rate` keeps the towers' own reload time and is what a frame of a big base
+//! costs in play. `every frame` has every tower fire on every frame, so it mostly measures
+//! spawning projectiles and recycling them through the pool.
+
+use std::{
+    f32::consts::TAU,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use base_defense::{
+    add_simulation,
+    assets::GameAssets,
+    constants::*,
+    enemy::{Enemy, EnemyKind},
+    hp_bar::Health,
+    map::MapSettings,
+    networking::{NetworkRole, NetworkingPlugin},
+    power::PowerConsumer,
+    tower::{AttackTimer, Tower},
+};
+use bevy::{
+    ecs::system::CommandQueue,
+    hierarchy::HierarchyPlugin,
+    prelude::*,
+    time::{create_time_channels, TimeSender},
+    transform::TransformPlugin,
+};
+use criterion::{criterion_group, criterion_main, Criterion};
+
+const TOWERS: usize = 200;
+// One frame at 60 fps, however long the update really took
+const FRAME: Duration = Duration::from_micros(16_667);
+// Shorter than a frame, so every tower fires every frame
+const EVERY_FRAME: Duration = Duration::from_micros(1);
+
+/// Advances the app's clock by exactly one `FRAME` per update, so timers run at game speed.
+struct FrameClock {
+    sender: TimeSender,
+    now: Instant,
+}
+
+impl FrameClock {
+    fn install(app: &mut App) -> FrameClock {
+        let (sender, receiver) = create_time_channels();
+        app.insert_resource(receiver);
+        FrameClock {
+            sender,
+            now: Instant::now(),
+        }
+    }
+
+    fn update(&mut self, app: &mut App) {
+        self.now += FRAME;
+        self.sender.0.send(self.now).unwrap();
+        app.update();
+    }
+}
+
+/// Towers stacked on top of an enemy that can't die, so every shot hits on the next frame
+/// and its projectile goes back to the pool. Without `fire_interval`, towers reload at
+/// their real rate, spread out so they don't all fire on the same frame.
+fn scenario(fire_interval: Option<Duration>) -> (App, FrameClock) {
+    let mut app = App::new();
+    app.add_plugins(MinimalPlugins)
+        .add_plugin(TransformPlugin)
+        .add_plugin(HierarchyPlugin);
+    add_simulation(
+        &mut app,
+        NetworkingPlugin::new(
+            NetworkRole::Singleplayer,
+            SocketAddr::from(([127, 0, 0, 1], DEFAULT_PORT)),
+            DEFAULT_USERNAME.to_string(),
+        ),
+        MapSettings::new(MIN_MAP_SIZE, DEFAULT_SEED),
+        DEFAULT_SEED,
+    );
+    let mut clock = FrameClock::install(&mut app);
+
+    let mut queue = CommandQueue::default();
+    let mut commands = Commands::new(&mut queue, &app.world);
+    let assets = app.world.resource::<GameAssets>();
+    let target = Enemy::new(&mut commands, Vec3::ZERO, EnemyKind::Melee, assets);
+    let mut towers = Vec::with_capacity(TOWERS);
+    for i in 0..TOWERS {
+        // Spread within the target's hitbox so towers face different ways
+        let angle = i as f32 / TOWERS as f32 * TAU;
+        let offset = Vec2::new(angle.cos(), angle.sin()) * TILE_SIZE * 0.2;
+        let tower = Tower::create_tower(&mut commands, offset.extend(BUILDING_LAYER), assets);
+        // Not placed as a building, so the power grid leaves it fully powered
+        commands.entity(tower).insert(PowerConsumer {
+            satisfaction: 1.0,
+            ..PowerConsumer::new(TOWER_POWER_DEMAND)
+        });
+        if let Some(interval) = fire_interval {
+            commands.entity(tower).insert(AttackTimer {
+                timer: Timer::new(interval, true),
+            });
+        }
+        towers.push(tower);
+    }
+    queue.apply(&mut app.world);
+    app.world
+        .get_mut::<Health>(target)
+        .unwrap()
+        .make_invulnerable(f32::INFINITY);
+    if fire_interval.is_none() {
+        for (i, &tower) in towers.iter().enumerate() {
+            let mut attack_timer = app.world.get_mut::<AttackTimer>(tower).unwrap();
+            let reload = attack_timer.timer.duration();
+            attack_timer
+                .timer
+                .set_elapsed(reload.mul_f32(i as f32 / TOWERS as f32));
+        }
+    }
+
+    // Fill the pool before measuring, a full reload lets every tower fire once
+    let warm_up = match fire_interval {
+        Some(_) => 3,
+        None => 60,
+    };
+    for _ in 0..warm_up {
+        clock.update(&mut app);
+    }
+    (app, clock)
+}
+
+fn towers_firing(c: &mut Criterion) {
+    let (mut app, mut clock) = scenario(None);
+    c.bench_function("200 towers firing, real fire rate", |b| {
+        b.iter(|| clock.update(&mut app))
+    });
+
+    let (mut app, mut clock) = scenario(Some(EVERY_FRAME));
+    c.bench_function("200 towers firing, every frame", |b| {
+        b.iter(|| clock.update(&mut app))
+    });
+}
+
+criterion_group!(benches, towers_firing);
+criterion_main!(benches);
@@ -0,0 +1,26 @@
+use bevy::prelude::*;
+
+/// Textures used by simulation entities, loaded once. Without an `AssetServer`, as on a
+/// headless server, the handles are left empty since nothing is drawn anyway.
+pub struct GameAssets {
+    pub tower: Handle<Image>,
+    pub enemy: Handle<Image>,
+    pub projectile: Handle<Image>,
+}
+
+impl FromWorld for GameAssets {
+    fn from_world(world: &mut World) -> Self {
+        match world.get_resource::<AssetServer>() {
+            Some(asset_server) => GameAssets {
+                tower: asset_server.load("sprites/tower.png"),
+                enemy: asset_server.load("sprites/enemy.png"),
+                projectile: asset_server.load("sprites/projectile.png"),
+            },
+            None => GameAssets {
+                tower: Handle::default(),
+                enemy: Handle::default(),
+                projectile: Handle::default(),
+            },
+        }
+    }
+}
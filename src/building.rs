@@ -1,54 +1,1280 @@
-use crate::{constants::*, PlayerResources};
+use crate::{
+    assets::GameAssets,
+    combat_text::RewardEvent,
+    constants::*,
+    economy::{Amount, Inventory, LedgerSource, PlayerResources, ResourceAmount, ResourceKind},
+    hp_bar::{create_bar, create_hp_bar, BarKind, Health},
+    lifecycle::Despawning,
+    map::{tile_to_world, TileGrid, NEIGHBOURS},
+    networking::{run_if_authority, PlayerLeft},
+    power::{PowerNode, PowerSource},
+    projectile::Faction,
+    tower::{AttackTimer, Tower},
+};
 use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::fmt;
 
 pub struct BuildingPlugin;
 
 impl Plugin for BuildingPlugin {
     fn build(&self, app: &mut App) {
-        app.add_system(update_miners);
+        app.add_event::<BuildRequest>()
+            .add_event::<ActionRejected>()
+            .add_event::<UpgradeRequest>()
+            .add_event::<BuildingUpgraded>()
+            .add_event::<SellRequest>()
+            .add_event::<RepairRequest>()
+            .init_resource::<RefundRate>()
+            .add_system(update_construction)
+            .add_system_set(
+                // Only whoever owns the simulation changes buildings, clients mirror the server
+                SystemSet::new()
+                    .with_run_criteria(run_if_authority)
+                    .with_system(handle_build_requests)
+                    .with_system(update_extractors)
+                    .with_system(update_refineries)
+                    .with_system(apply_upgrades)
+                    .with_system(sell_buildings)
+                    .with_system(start_repairs)
+                    .with_system(stop_departed_repairs)
+                    .with_system(update_repair_stations)
+                    .with_system(repair_buildings)
+                    .with_system(destroy_buildings),
+            )
+            .add_system_to_stage(CoreStage::PostUpdate, update_wall_connectors);
     }
 }
 
-fn update_miners(
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+pub enum BuildingKind {
+    Tower,
+    Miner,
+    Quarry,
+    Lumberyard,
+    OreMine,
+    Smelter,
+    Generator,
+    Pylon,
+    Wall,
+    RepairStation,
+}
+
+pub struct BuildingDef {
+    pub name: &'static str,
+    pub cost: &'static [ResourceAmount],
+    pub health: f32,
+    /// Seconds between placing the building and it becoming functional.
+    pub build_time: f32,
+    pub color: Color,
+    /// Deposit the building has to be placed on, if any.
+    pub deposit: Option<ResourceKind>,
+    /// Resource produced every second by an extractor.
+    pub extracts: Option<ResourceAmount>,
+    pub recipe: Option<Recipe>,
+    /// Total number of upgrades a single building can receive.
+    pub max_tier: u32,
+    pub upgrades: &'static [UpgradeDef],
+}
+
+/// Conversion performed by a refining building every `time` seconds.
+pub struct Recipe {
+    pub inputs: &'static [ResourceAmount],
+    pub outputs: &'static [ResourceAmount],
+    pub time: f32,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum UpgradeStat {
+    Damage,
+    FireRate,
+    Range,
+    MiningYield,
+    Health,
+}
+
+pub struct UpgradeDef {
+    pub name: &'static str,
+    pub stat: UpgradeStat,
+    /// Multiplier applied to the stat every time the upgrade is bought.
+    pub factor: f32,
+    /// Gold price of the first purchase.
+    pub cost: Amount,
+}
+
+impl UpgradeDef {
+    /// Upgrades get pricier the higher the building's tier already is.
+    pub fn cost_at(&self, tier: u32) -> Amount {
+        self.cost * (tier + 1) as i64
+    }
+}
+
+const HEALTH_UPGRADE: UpgradeDef = UpgradeDef {
+    name: "Health",
+    stat: UpgradeStat::Health,
+    factor: 1.3,
+    cost: Amount::whole(20),
+};
+
+const YIELD_UPGRADE: UpgradeDef = UpgradeDef {
+    name: "Mining yield",
+    stat: UpgradeStat::MiningYield,
+    factor: 1.3,
+    cost: Amount::whole(40),
+};
+
+const TOWER_DEF: BuildingDef = BuildingDef {
+    name: "Tower",
+    cost: &[
+        (ResourceKind::Gold, Amount::whole(40)),
+        (ResourceKind::Stone, Amount::whole(10)),
+    ],
+    health: 100.0,
+    build_time: 5.0,
+    color: COLOR_TOWER,
+    deposit: None,
+    extracts: None,
+    recipe: None,
+    max_tier: 5,
+    upgrades: &[
+        UpgradeDef {
+            name: "Damage",
+            stat: UpgradeStat::Damage,
+            factor: 1.25,
+            cost: Amount::whole(40),
+        },
+        UpgradeDef {
+            name: "Fire rate",
+            stat: UpgradeStat::FireRate,
+            factor: 1.2,
+            cost: Amount::whole(40),
+        },
+        UpgradeDef {
+            name: "Range",
+            stat: UpgradeStat::Range,
+            factor: 1.15,
+            cost: Amount::whole(30),
+        },
+        UpgradeDef {
+            cost: Amount::whole(25),
+            ..HEALTH_UPGRADE
+        },
+    ],
+};
+
+const MINER_DEF: BuildingDef = BuildingDef {
+    name: "Miner",
+    cost: &[(ResourceKind::Gold, Amount::whole(30))],
+    health: 80.0,
+    build_time: 4.0,
+    color: COLOR_MINER,
+    deposit: Some(ResourceKind::Gold),
+    extracts: Some((ResourceKind::Gold, Amount::whole(10))),
+    recipe: None,
+    max_tier: 3,
+    upgrades: &[YIELD_UPGRADE, HEALTH_UPGRADE],
+};
+
+const QUARRY_DEF: BuildingDef = BuildingDef {
+    name: "Quarry",
+    cost: &[
+        (ResourceKind::Gold, Amount::whole(20)),
+        (ResourceKind::Wood, Amount::whole(5)),
+    ],
+    health: 80.0,
+    build_time: 4.0,
+    color: COLOR_QUARRY,
+    deposit: Some(ResourceKind::Stone),
+    extracts: Some((ResourceKind::Stone, Amount::whole(4))),
+    recipe: None,
+    max_tier: 3,
+    upgrades: &[YIELD_UPGRADE, HEALTH_UPGRADE],
+};
+
+const LUMBERYARD_DEF: BuildingDef = BuildingDef {
+    name: "Lumberyard",
+    cost: &[(ResourceKind::Gold, Amount::whole(20))],
+    health: 60.0,
+    build_time: 3.0,
+    color: COLOR_LUMBERYARD,
+    deposit: Some(ResourceKind::Wood),
+    extracts: Some((ResourceKind::Wood, Amount::whole(4))),
+    recipe: None,
+    max_tier: 3,
+    upgrades: &[YIELD_UPGRADE, HEALTH_UPGRADE],
+};
+
+const ORE_MINE_DEF: BuildingDef = BuildingDef {
+    name: "Ore mine",
+    cost: &[
+        (ResourceKind::Gold, Amount::whole(25)),
+        (ResourceKind::Stone, Amount::whole(10)),
+    ],
+    health: 80.0,
+    build_time: 5.0,
+    color: COLOR_ORE_MINE,
+    deposit: Some(ResourceKind::Ore),
+    extracts: Some((ResourceKind::Ore, Amount::whole(3))),
+    recipe: None,
+    max_tier: 3,
+    upgrades: &[YIELD_UPGRADE, HEALTH_UPGRADE],
+};
+
+const SMELTER_DEF: BuildingDef = BuildingDef {
+    name: "Smelter",
+    cost: &[
+        (ResourceKind::Gold, Amount::whole(40)),
+        (ResourceKind::Stone, Amount::whole(20)),
+    ],
+    health: 100.0,
+    build_time: 6.0,
+    color: COLOR_SMELTER,
+    deposit: None,
+    extracts: None,
+    recipe: Some(Recipe {
+        inputs: &[
+            (ResourceKind::Ore, Amount::whole(2)),
+            (ResourceKind::Wood, Amount::whole(1)),
+        ],
+        outputs: &[(ResourceKind::Metal, Amount::whole(1))],
+        time: 3.0,
+    }),
+    max_tier: 2,
+    upgrades: &[HEALTH_UPGRADE],
+};
+
+const GENERATOR_DEF: BuildingDef = BuildingDef {
+    name: "Generator",
+    cost: &[
+        (ResourceKind::Gold, Amount::whole(30)),
+        (ResourceKind::Wood, Amount::whole(10)),
+    ],
+    health: 80.0,
+    build_time: 4.0,
+    color: COLOR_GENERATOR,
+    deposit: None,
+    extracts: None,
+    recipe: None,
+    max_tier: 3,
+    upgrades: &[HEALTH_UPGRADE],
+};
+
+const PYLON_DEF: BuildingDef = BuildingDef {
+    name: "Pylon",
+    cost: &[
+        (ResourceKind::Gold, Amount::whole(10)),
+        (ResourceKind::Stone, Amount::whole(5)),
+    ],
+    health: 40.0,
+    build_time: 2.0,
+    color: COLOR_PYLON,
+    deposit: None,
+    extracts: None,
+    recipe: None,
+    max_tier: 2,
+    upgrades: &[HEALTH_UPGRADE],
+};
+
+const WALL_DEF: BuildingDef = BuildingDef {
+    name: "Wall",
+    cost: &[(ResourceKind::Stone, Amount::whole(5))],
+    health: 150.0,
+    build_time: 1.0,
+    color: COLOR_WALL,
+    deposit: None,
+    extracts: None,
+    recipe: None,
+    max_tier: 3,
+    upgrades: &[UpgradeDef {
+        name: "Health",
+        stat: UpgradeStat::Health,
+        factor: 1.5,
+        cost: Amount::whole(10),
+    }],
+};
+
+const REPAIR_STATION_DEF: BuildingDef = BuildingDef {
+    name: "Repair station",
+    cost: &[
+        (ResourceKind::Gold, Amount::whole(40)),
+        (ResourceKind::Wood, Amount::whole(10)),
+        (ResourceKind::Metal, Amount::whole(5)),
+    ],
+    health: 80.0,
+    build_time: 6.0,
+    color: COLOR_REPAIR_STATION,
+    deposit: None,
+    extracts: None,
+    recipe: None,
+    max_tier: 3,
+    upgrades: &[
+        UpgradeDef {
+            name: "Range",
+            stat: UpgradeStat::Range,
+            factor: 1.25,
+            cost: Amount::whole(30),
+        },
+        HEALTH_UPGRADE,
+    ],
+};
+
+impl BuildingKind {
+    pub const ALL: [BuildingKind; 10] = [
+        BuildingKind::Tower,
+        BuildingKind::Miner,
+        BuildingKind::Quarry,
+        BuildingKind::Lumberyard,
+        BuildingKind::OreMine,
+        BuildingKind::Smelter,
+        BuildingKind::Generator,
+        BuildingKind::Pylon,
+        BuildingKind::Wall,
+        BuildingKind::RepairStation,
+    ];
+
+    pub fn def(self) -> &'static BuildingDef {
+        match self {
+            BuildingKind::Tower => &TOWER_DEF,
+            BuildingKind::Miner => &MINER_DEF,
+            BuildingKind::Quarry => &QUARRY_DEF,
+            BuildingKind::Lumberyard => &LUMBERYARD_DEF,
+            BuildingKind::OreMine => &ORE_MINE_DEF,
+            BuildingKind::Smelter => &SMELTER_DEF,
+            BuildingKind::Generator => &GENERATOR_DEF,
+            BuildingKind::Pylon => &PYLON_DEF,
+            BuildingKind::Wall => &WALL_DEF,
+            BuildingKind::RepairStation => &REPAIR_STATION_DEF,
+        }
+    }
+}
+
+#[derive(Component)]
+pub struct Building {
+    pub kind: BuildingKind,
+    pub tile: IVec2,
+    pub tier: u32,
+    /// Upgrades bought so far, in order, so clients can repeat them.
+    pub upgrades: Vec<usize>,
+    /// Resources spent on the building so far, including upgrades.
+    pub invested: Inventory,
+}
+
+impl Building {
+    pub fn refund(&self, rate: &RefundRate) -> Vec<ResourceAmount> {
+        self.invested
+            .amounts()
+            .into_iter()
+            .map(|(kind, amount)| (kind, amount.scale(rate.0)))
+            .collect()
+    }
+}
+
+/// Fraction of the invested resources given back when a building is sold.
+pub struct RefundRate(pub f32);
+
+impl Default for RefundRate {
+    fn default() -> Self {
+        RefundRate(SELL_REFUND_RATE)
+    }
+}
+
+/// Place a new building. `player` is the client that asked for it, `None` when local.
+pub struct BuildRequest {
+    pub kind: BuildingKind,
+    pub tile: IVec2,
+    pub player: Option<u64>,
+}
+
+/// A player's action was refused, with a reason to show them.
+pub struct ActionRejected {
+    pub player: Option<u64>,
+    pub reason: String,
+}
+
+/// Buy upgrade number `upgrade` of the building's definition. `player` is the client that
+/// asked for it, `None` when local.
+pub struct UpgradeRequest {
+    pub building: Entity,
+    pub upgrade: usize,
+    pub player: Option<u64>,
+}
+
+/// An upgrade was bought and applied, leaving the building at `tier`.
+pub struct BuildingUpgraded {
+    pub building: Entity,
+    pub upgrade: usize,
+    pub tier: u32,
+}
+
+/// Demolish a building, refunding part of what was spent on it. `player` is the client that
+/// asked for it, `None` when local.
+pub struct SellRequest {
+    pub building: Entity,
+    pub player: Option<u64>,
+}
+
+/// Building that has been placed but doesn't work yet. Its health grows from
+/// `CONSTRUCTION_START_HEALTH` of the maximum as construction progresses.
+#[derive(Component)]
+pub struct UnderConstruction {
+    pub timer: Timer,
+    bar: Entity,
+}
+
+/// Start restoring a damaged building's health, paid for per HP as it heals. `player` is
+/// the client that asked for it, `None` when local.
+pub struct RepairRequest {
+    pub building: Entity,
+    pub player: Option<u64>,
+}
+
+/// Building whose health is being restored at `rate` HP per second. `player` ordered the
+/// repair, `None` when it is local or done by a repair station.
+#[derive(Component)]
+pub struct Repairing {
+    pub rate: f32,
+    pub player: Option<u64>,
+}
+
+/// Estimated gold needed to bring a building back to full health.
+pub fn repair_cost(health: &Health) -> Amount {
+    REPAIR_COST_PER_HP.scale((health.max - health.current).max(0.0))
+}
+
+/// Why a building couldn't be placed.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PlacementError {
+    Blocked,
+    MissingDeposit(ResourceKind),
+    CannotAfford,
+}
+
+impl fmt::Display for PlacementError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PlacementError::Blocked => write!(f, "the tile is already taken"),
+            PlacementError::MissingDeposit(kind) => write!(f, "it needs a {} deposit", kind.name()),
+            PlacementError::CannotAfford => write!(f, "not enough resources"),
+        }
+    }
+}
+
+/// Why a building couldn't be upgraded.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum UpgradeError {
+    NoSuchUpgrade,
+    UnderConstruction,
+    MaxTier,
+    CannotAfford,
+}
+
+impl fmt::Display for UpgradeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UpgradeError::NoSuchUpgrade => write!(f, "it has no such upgrade"),
+            UpgradeError::UnderConstruction => write!(f, "it is still under construction"),
+            UpgradeError::MaxTier => write!(f, "it is already at the highest tier"),
+            UpgradeError::CannotAfford => write!(f, "not enough resources"),
+        }
+    }
+}
+
+/// Pays for a building and spawns it on `tile`. Fails when the tile is already taken,
+/// lacks the deposit the building needs or the player can't afford it.
+pub fn place_building(
+    commands: &mut Commands,
+    kind: BuildingKind,
+    tile: IVec2,
+    grid: &mut TileGrid,
+    res: &mut PlayerResources,
+    assets: &GameAssets,
+) -> Result<Entity, PlacementError> {
+    let def = kind.def();
+    if !grid.is_passable(tile) {
+        return Err(PlacementError::Blocked);
+    }
+    if let Some(deposit) = def.deposit {
+        if grid.deposit(tile) != Some(deposit) {
+            return Err(PlacementError::MissingDeposit(deposit));
+        }
+    }
+    if !res.spend(def.cost, LedgerSource::Construction) {
+        return Err(PlacementError::CannotAfford);
+    }
+    Ok(spawn_building(commands, kind, tile, grid, assets))
+}
+
+/// Spawns a building on `tile` without any checks or payment. Clients use this directly
+/// for buildings the server has already accepted.
+pub fn spawn_building(
+    commands: &mut Commands,
+    kind: BuildingKind,
+    tile: IVec2,
+    grid: &mut TileGrid,
+    assets: &GameAssets,
+) -> Entity {
+    let def = kind.def();
+    let translation = tile_to_world(tile).extend(BUILDING_LAYER);
+    let building = match kind {
+        BuildingKind::Tower => Tower::create_tower(commands, translation, assets),
+        BuildingKind::Wall => Wall::new(commands, translation),
+        BuildingKind::RepairStation => RepairStation::new(commands, translation, assets),
+        BuildingKind::Generator => {
+            let generator = spawn_building_sprite(commands, translation, def, assets);
+            commands
+                .entity(generator)
+                .insert(PowerSource {
+                    output: GENERATOR_OUTPUT,
+                })
+                .insert(PowerNode {
+                    radius: GENERATOR_RADIUS,
+                });
+            generator
+        }
+        BuildingKind::Pylon => {
+            let pylon = spawn_building_sprite(commands, translation, def, assets);
+            commands.entity(pylon).insert(PowerNode {
+                radius: PYLON_RADIUS,
+            });
+            pylon
+        }
+        _ => spawn_building_sprite(commands, translation, def, assets),
+    };
+    if let Some((resource, amount)) = def.extracts {
+        commands.entity(building).insert(Extractor {
+            timer: Timer::from_seconds(1.0, true),
+            resource,
+            amount,
+        });
+    }
+    if let Some(recipe) = &def.recipe {
+        commands.entity(building).insert(Refinery {
+            timer: Timer::from_seconds(recipe.time, true),
+        });
+    }
+    commands
+        .entity(building)
+        .insert(Building {
+            kind,
+            tile,
+            tier: 0,
+            upgrades: Vec::new(),
+            invested: Inventory::new(def.cost),
+        })
+        .insert(Faction::Player)
+        .insert(building_health(kind, def));
+    let hp_bar = create_hp_bar(
+        commands,
+        Vec2::new(0.0, TILE_SIZE * 0.5),
+        Vec2::new(TILE_SIZE * 0.85, TILE_SIZE * 0.1),
+        building,
+    );
+    let progress_bar = create_bar(
+        commands,
+        Vec2::new(0.0, -TILE_SIZE * 0.5),
+        Vec2::new(TILE_SIZE * 0.85, TILE_SIZE * 0.1),
+        building,
+        BarKind::Construction,
+    );
+    commands
+        .entity(building)
+        .insert(UnderConstruction {
+            timer: Timer::from_seconds(def.build_time, false),
+            bar: progress_bar,
+        })
+        .push_children(&[hp_bar, progress_bar]);
+    grid.occupy(tile, building);
+    building
+}
+
+fn handle_build_requests(
+    mut commands: Commands,
+    mut build_events: EventReader<BuildRequest>,
+    mut rejections: EventWriter<ActionRejected>,
+    mut grid: ResMut<TileGrid>,
+    mut res: ResMut<PlayerResources>,
+    assets: Res<GameAssets>,
+) {
+    for request in build_events.iter() {
+        let placed = place_building(
+            &mut commands,
+            request.kind,
+            request.tile,
+            &mut grid,
+            &mut res,
+            &assets,
+        );
+        if let Err(err) = placed {
+            rejections.send(ActionRejected {
+                player: request.player,
+                reason: format!("Can't build {} here: {}", request.kind.def().name, err),
+            });
+        }
+    }
+}
+
+fn sell_buildings(
+    mut commands: Commands,
+    mut sell_events: EventReader<SellRequest>,
+    mut rejections: EventWriter<ActionRejected>,
+    mut grid: ResMut<TileGrid>,
+    mut res: ResMut<PlayerResources>,
+    rate: Res<RefundRate>,
+    q_buildings: Query<(&Building, &Transform, &Health), Without<Despawning>>,
+    mut reward_events: EventWriter<RewardEvent>,
+) {
+    let mut sold = Vec::new();
+    for request in sell_events.iter() {
+        // Already destroyed or sold this frame, nothing left to sell
+        let found = q_buildings
+            .get(request.building)
+            .ok()
+            .filter(|(_, _, health)| health.current > 0.0 && !sold.contains(&request.building));
+        let (building, transform, _) = match found {
+            Some(building) => building,
+            None => {
+                rejections.send(ActionRejected {
+                    player: request.player,
+                    reason: "Can't sell: the building is already gone".to_string(),
+                });
+                continue;
+            }
+        };
+        sold.push(request.building);
+        let refund = building.refund(&rate);
+        res.earn_all(&refund, LedgerSource::Sale);
+        reward_events.send_batch(refund.into_iter().map(|(resource, amount)| RewardEvent {
+            position: transform.translation,
+            resource,
+            amount,
+        }));
+        grid.free(building.tile);
+        commands.entity(request.building).insert(Despawning);
+    }
+}
+
+fn start_repairs(
+    mut commands: Commands,
+    mut repair_events: EventReader<RepairRequest>,
+    q_buildings: Query<
+        &Health,
+        (
+            With<Building>,
+            Without<Repairing>,
+            Without<UnderConstruction>,
+        ),
+    >,
+) {
+    for request in repair_events.iter() {
+        if let Ok(health) = q_buildings.get(request.building) {
+            if health.current < health.max {
+                commands.entity(request.building).insert(Repairing {
+                    rate: REPAIR_RATE,
+                    player: request.player,
+                });
+            }
+        }
+    }
+}
+
+/// Repairs a player ordered are paid out of the shared stockpile, so they stop when that
+/// player leaves. Builds, sales and upgrades apply at once and have nothing left to cancel.
+fn stop_departed_repairs(
+    mut commands: Commands,
+    mut left: EventReader<PlayerLeft>,
+    q_repairing: Query<(Entity, &Repairing)>,
+) {
+    for player in left.iter() {
+        for (entity, repairing) in q_repairing.iter() {
+            if repairing.player == Some(player.client_id) {
+                commands.entity(entity).remove::<Repairing>();
+            }
+        }
+    }
+}
+
+fn update_repair_stations(
+    mut commands: Commands,
+    res: Res<PlayerResources>,
+    q_stations: Query<(&RepairStation, &Transform), Without<UnderConstruction>>,
+    q_buildings: Query<
+        (Entity, &Health, &Transform),
+        (
+            With<Building>,
+            Without<Repairing>,
+            Without<UnderConstruction>,
+        ),
+    >,
+) {
+    if res.get(ResourceKind::Gold) < REPAIR_COST_PER_HP {
+        return;
+    }
+    for (station, station_transform) in q_stations.iter() {
+        let pos = station_transform.translation.truncate();
+        for (entity, health, transform) in q_buildings.iter() {
+            if health.current < health.max
+                && (transform.translation.truncate() - pos).length() <= station.range
+            {
+                commands.entity(entity).insert(Repairing {
+                    rate: station.rate,
+                    player: None,
+                });
+            }
+        }
+    }
+}
+
+fn repair_buildings(
+    mut commands: Commands,
+    mut res: ResMut<PlayerResources>,
+    mut q_repairing: Query<(Entity, &mut Health, &Repairing)>,
+    time: Res<Time>,
+) {
+    for (entity, mut health, repairing) in q_repairing.iter_mut() {
+        let affordable = res.get(ResourceKind::Gold).0 as f32 / REPAIR_COST_PER_HP.0 as f32;
+        let heal = (repairing.rate * time.delta_seconds())
+            .min(health.max - health.current)
+            .min(affordable)
+            .max(0.0);
+        let cost = REPAIR_COST_PER_HP.scale(heal);
+        if res.spend(&[(ResourceKind::Gold, cost)], LedgerSource::Repair) {
+            health.current += heal;
+        }
+        if health.current >= health.max || res.get(ResourceKind::Gold) < REPAIR_COST_PER_HP {
+            commands.entity(entity).remove::<Repairing>();
+        }
+    }
+}
+
+fn destroy_buildings(
+    mut commands: Commands,
+    mut grid: ResMut<TileGrid>,
+    q_buildings: Query<(Entity, &Building, &Health), Without<Despawning>>,
+) {
+    for (entity, building, health) in q_buildings.iter() {
+        if health.current <= 0.0 {
+            grid.free(building.tile);
+            commands.entity(entity).insert(Despawning);
+        }
+    }
+}
+
+fn update_construction(
+    mut commands: Commands,
+    mut q_construction: Query<(Entity, &mut UnderConstruction, &mut Health)>,
+    time: Res<Time>,
+) {
+    for (entity, mut construction, mut health) in q_construction.iter_mut() {
+        construction.timer.tick(time.delta());
+        let build_time = construction.timer.duration().as_secs_f32();
+        let growth = health.max * (1.0 - CONSTRUCTION_START_HEALTH) * time.delta_seconds();
+        health.current = (health.current + growth / build_time).min(health.max);
+        if construction.timer.finished() {
+            commands.entity(construction.bar).insert(Despawning);
+            commands.entity(entity).remove::<UnderConstruction>();
+        }
+    }
+}
+
+fn apply_upgrades(
+    mut upgrade_events: EventReader<UpgradeRequest>,
+    mut upgraded: EventWriter<BuildingUpgraded>,
+    mut rejections: EventWriter<ActionRejected>,
     mut res: ResMut<PlayerResources>,
-    mut q_miners: Query<&mut Miner>,
+    mut q_buildings: UpgradeTargets,
+    q_construction: Query<(), With<UnderConstruction>>,
+) {
+    for request in upgrade_events.iter() {
+        let building = match q_buildings.get_component::<Building>(request.building) {
+            Ok(building) => building,
+            Err(_) => continue,
+        };
+        let def = building.kind.def();
+        let paid = match def.upgrades.get(request.upgrade) {
+            None => Err(UpgradeError::NoSuchUpgrade),
+            Some(_) if q_construction.get(request.building).is_ok() => {
+                Err(UpgradeError::UnderConstruction)
+            }
+            Some(_) if building.tier >= def.max_tier => Err(UpgradeError::MaxTier),
+            Some(upgrade) => {
+                let cost = [(ResourceKind::Gold, upgrade.cost_at(building.tier))];
+                if res.spend(&cost, LedgerSource::Upgrade) {
+                    Ok(())
+                } else {
+                    Err(UpgradeError::CannotAfford)
+                }
+            }
+        };
+        match paid {
+            Ok(()) => {
+                if let Some(tier) =
+                    upgrade_building(&mut q_buildings, request.building, request.upgrade)
+                {
+                    upgraded.send(BuildingUpgraded {
+                        building: request.building,
+                        upgrade: request.upgrade,
+                        tier,
+                    });
+                }
+            }
+            Err(err) => rejections.send(ActionRejected {
+                player: request.player,
+                reason: format!("Can't upgrade the {}: {}", def.name, err),
+            }),
+        }
+    }
+}
+
+/// Everything an upgrade can change on a building.
+pub type UpgradeTargets<'w, 's> = Query<
+    'w,
+    's,
+    (
+        &'static mut Building,
+        &'static mut Health,
+        &'static mut Sprite,
+        Option<&'static mut Tower>,
+        Option<&'static mut AttackTimer>,
+        Option<&'static mut Extractor>,
+        Option<&'static mut RepairStation>,
+    ),
+>;
+
+/// Applies one purchase of upgrade number `upgrade` to the building's stats, tier and
+/// colour, returning the new tier. Payment and limits are up to the caller, so clients can
+/// repeat upgrades the server already accepted.
+pub fn upgrade_building(
+    q_buildings: &mut UpgradeTargets,
+    entity: Entity,
+    upgrade: usize,
+) -> Option<u32> {
+    let (mut building, mut health, mut sprite, tower, attack_timer, extractor, station) =
+        q_buildings.get_mut(entity).ok()?;
+    let def = building.kind.def();
+    let upgrade_def = def.upgrades.get(upgrade)?;
+    let cost = [(ResourceKind::Gold, upgrade_def.cost_at(building.tier))];
+    building.invested.add_all(&cost);
+    building.tier += 1;
+    building.upgrades.push(upgrade);
+
+    match upgrade_def.stat {
+        UpgradeStat::Damage => {
+            if let Some(mut tower) = tower {
+                tower.damage *= upgrade_def.factor;
+            }
+        }
+        UpgradeStat::Range => {
+            if let Some(mut tower) = tower {
+                tower.range *= upgrade_def.factor;
+            }
+            if let Some(mut station) = station {
+                station.range *= upgrade_def.factor;
+            }
+        }
+        UpgradeStat::FireRate => {
+            if let Some(mut attack_timer) = attack_timer {
+                let duration = attack_timer.timer.duration().div_f32(upgrade_def.factor);
+                attack_timer.timer.set_duration(duration);
+            }
+        }
+        UpgradeStat::MiningYield => {
+            if let Some(mut extractor) = extractor {
+                extractor.amount = extractor.amount.scale(upgrade_def.factor);
+            }
+        }
+        UpgradeStat::Health => {
+            health.max *= upgrade_def.factor;
+            health.current *= upgrade_def.factor;
+            health.max_shield *= upgrade_def.factor;
+        }
+    }
+    sprite.color = tier_color(def.color, building.tier);
+    Some(building.tier)
+}
+
+/// Higher tiers are drawn progressively lighter than the base colour.
+fn tier_color(base: Color, tier: u32) -> Color {
+    let t = (tier as f32 * 0.15).min(0.6);
+    Color::rgb(
+        base.r() + (1.0 - base.r()) * t,
+        base.g() + (1.0 - base.g()) * t,
+        base.b() + (1.0 - base.b()) * t,
+    )
+}
+
+fn update_extractors(
+    mut res: ResMut<PlayerResources>,
+    mut q_extractors: Query<(&Building, &Transform, &mut Extractor), Without<UnderConstruction>>,
+    mut reward_events: EventWriter<RewardEvent>,
     time: Res<Time>,
 ) {
-    for mut miner in q_miners.iter_mut() {
-        miner.mine_timer.tick(time.delta());
-        if miner.mine_timer.just_finished() {
-            res.gold += miner.gold;
+    for (building, transform, mut extractor) in q_extractors.iter_mut() {
+        extractor.timer.tick(time.delta());
+        if extractor.timer.just_finished() {
+            let source = LedgerSource::Production(building.kind);
+            res.earn(extractor.resource, extractor.amount, source);
+            reward_events.send(RewardEvent {
+                position: transform.translation,
+                resource: extractor.resource,
+                amount: extractor.amount,
+            });
         }
     }
 }
 
+fn update_refineries(
+    mut res: ResMut<PlayerResources>,
+    mut q_refineries: Query<(&Building, &Transform, &mut Refinery), Without<UnderConstruction>>,
+    mut reward_events: EventWriter<RewardEvent>,
+    time: Res<Time>,
+) {
+    for (building, transform, mut refinery) in q_refineries.iter_mut() {
+        refinery.timer.tick(time.delta());
+        if !refinery.timer.just_finished() {
+            continue;
+        }
+        if let Some(recipe) = &building.kind.def().recipe {
+            if res.spend(recipe.inputs, LedgerSource::Refining(building.kind)) {
+                res.earn_all(recipe.outputs, LedgerSource::Production(building.kind));
+                reward_events.send_batch(recipe.outputs.iter().map(|&(resource, amount)| {
+                    RewardEvent {
+                        position: transform.translation,
+                        resource,
+                        amount,
+                    }
+                }));
+            }
+        }
+    }
+}
+
+/// Produces `amount` of `resource` every second.
+#[derive(Component)]
+pub struct Extractor {
+    timer: Timer,
+    pub resource: ResourceKind,
+    pub amount: Amount,
+}
+
+/// Runs the building definition's recipe whenever the timer finishes.
 #[derive(Component)]
-pub struct Miner {
-    mine_timer: Timer,
-    gold: f32,
+pub struct Refinery {
+    timer: Timer,
 }
 
-impl Miner {
-    pub fn new(mut commands: Commands, translation: Vec3, asset_server: Res<AssetServer>) {
-        let trans = Transform {
-            translation,
+/// Plain textured sprite for buildings that don't need a custom look.
+fn spawn_building_sprite(
+    commands: &mut Commands,
+    translation: Vec3,
+    def: &BuildingDef,
+    assets: &GameAssets,
+) -> Entity {
+    commands
+        .spawn_bundle(SpriteBundle {
+            sprite: Sprite {
+                color: def.color,
+                custom_size: Some(Vec2::splat(TILE_SIZE * 0.7)),
+                ..Default::default()
+            },
+            texture: assets.tower.clone(),
+            transform: Transform {
+                translation,
+                ..Default::default()
+            },
             ..Default::default()
-        };
-        let miner = commands
+        })
+        .insert(Name::new(def.name))
+        .id()
+}
+
+#[derive(Component)]
+pub struct Wall;
+
+/// Arm reaching from a wall towards a neighbouring tile, shown only when that tile holds a wall
+/// too, so that adjacent segments look like one continuous wall.
+#[derive(Component)]
+struct WallConnector(IVec2);
+
+impl Wall {
+    pub fn new(commands: &mut Commands, translation: Vec3) -> Entity {
+        let connectors: Vec<Entity> = NEIGHBOURS
+            .iter()
+            .map(|&dir| {
+                let size = if dir.x != 0 {
+                    Vec2::new(TILE_SIZE * 0.25, TILE_SIZE * 0.3)
+                } else {
+                    Vec2::new(TILE_SIZE * 0.3, TILE_SIZE * 0.25)
+                };
+                commands
+                    .spawn_bundle(SpriteBundle {
+                        sprite: Sprite {
+                            color: COLOR_WALL,
+                            custom_size: Some(size),
+                            ..Default::default()
+                        },
+                        transform: Transform {
+                            translation: (dir.as_vec2() * TILE_SIZE * 0.375).extend(0.0),
+                            ..Default::default()
+                        },
+                        visibility: Visibility { is_visible: false },
+                        ..Default::default()
+                    })
+                    .insert(WallConnector(dir))
+                    .id()
+            })
+            .collect();
+
+        commands
             .spawn_bundle(SpriteBundle {
                 sprite: Sprite {
-                    color: COLOR_MINER,
+                    color: COLOR_WALL,
+                    custom_size: Some(Vec2::splat(TILE_SIZE * 0.5)),
+                    ..Default::default()
+                },
+                transform: Transform {
+                    translation,
+                    ..Default::default()
+                },
+                ..Default::default()
+            })
+            .insert(Wall)
+            .insert(Name::new("Wall"))
+            .push_children(&connectors)
+            .id()
+    }
+}
+
+fn update_wall_connectors(
+    grid: Res<TileGrid>,
+    q_walls: Query<&Building, With<Wall>>,
+    mut q_connectors: Query<(&WallConnector, &Parent, &mut Visibility)>,
+) {
+    if !grid.is_changed() {
+        return;
+    }
+    for (connector, parent, mut visibility) in q_connectors.iter_mut() {
+        if let Ok(building) = q_walls.get(parent.get()) {
+            visibility.is_visible = grid
+                .occupant(building.tile + connector.0)
+                .map_or(false, |neighbour| q_walls.contains(neighbour));
+        }
+    }
+}
+
+/// Automatically repairs damaged buildings within `range`, charging the usual price per HP.
+#[derive(Component)]
+pub struct RepairStation {
+    pub range: f32,
+    pub rate: f32,
+}
+
+impl RepairStation {
+    pub fn new(commands: &mut Commands, translation: Vec3, assets: &GameAssets) -> Entity {
+        commands
+            .spawn_bundle(SpriteBundle {
+                sprite: Sprite {
+                    color: COLOR_REPAIR_STATION,
                     custom_size: Some(Vec2::splat(TILE_SIZE * 0.7)),
                     ..Default::default()
                 },
-                texture: asset_server.load("sprites/tower.png"),
-                transform: trans,
+                texture: assets.tower.clone(),
+                transform: Transform {
+                    translation,
+                    ..Default::default()
+                },
                 ..Default::default()
             })
-            .insert(Miner {
-                mine_timer: Timer::from_seconds(1.0, true),
-                gold: 10.0,
+            .insert(RepairStation {
+                range: 4.0 * TILE_SIZE,
+                rate: REPAIR_RATE * 0.5,
             })
-            .insert(Name::new("Miner"));
+            .insert(Name::new("Repair station"))
+            .id()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{count_events, headless_app, send};
+
+    fn build(app: &mut App, kind: BuildingKind, tile: IVec2) {
+        send(
+            app,
+            BuildRequest {
+                kind,
+                tile,
+                player: None,
+            },
+        );
+        app.update();
+    }
+
+    fn building_at(app: &App, tile: IVec2) -> Option<Entity> {
+        app.world.resource::<TileGrid>().occupant(tile)
+    }
+
+    fn stock(app: &App, kind: ResourceKind) -> Amount {
+        app.world.resource::<PlayerResources>().get(kind)
+    }
+
+    fn upgrade(app: &mut App, building: Entity) {
+        send(
+            app,
+            UpgradeRequest {
+                building,
+                upgrade: 0,
+                player: None,
+            },
+        );
+        app.update();
+    }
+
+    #[test]
+    fn placing_pays_and_occupies_the_tile() {
+        let mut app = headless_app();
+        let stone = stock(&app, ResourceKind::Stone);
+        build(&mut app, BuildingKind::Wall, IVec2::ZERO);
+
+        let wall = building_at(&app, IVec2::ZERO).expect("wall was placed");
+        assert_eq!(
+            app.world.get::<Building>(wall).unwrap().kind,
+            BuildingKind::Wall
+        );
+        assert_eq!(stock(&app, ResourceKind::Stone), stone - WALL_DEF.cost[0].1);
+        assert_eq!(count_events::<ActionRejected>(&app), 0);
+    }
+
+    #[test]
+    fn placing_on_an_occupied_tile_is_rejected() {
+        let mut app = headless_app();
+        build(&mut app, BuildingKind::Wall, IVec2::ZERO);
+        let wall = building_at(&app, IVec2::ZERO).unwrap();
+        let stone = stock(&app, ResourceKind::Stone);
+        build(&mut app, BuildingKind::Wall, IVec2::ZERO);
+
+        assert_eq!(count_events::<ActionRejected>(&app), 1);
+        assert_eq!(building_at(&app, IVec2::ZERO), Some(wall));
+        assert_eq!(stock(&app, ResourceKind::Stone), stone);
+    }
+
+    #[test]
+    fn extractors_need_their_deposit() {
+        let mut app = headless_app();
+        app.world
+            .resource_mut::<TileGrid>()
+            .set_deposit(IVec2::ZERO, None);
+        let gold = stock(&app, ResourceKind::Gold);
+        build(&mut app, BuildingKind::Miner, IVec2::ZERO);
+
+        assert_eq!(count_events::<ActionRejected>(&app), 1);
+        assert!(building_at(&app, IVec2::ZERO).is_none());
+        assert_eq!(stock(&app, ResourceKind::Gold), gold);
+    }
+
+    #[test]
+    fn construction_completes_after_the_build_time() {
+        let mut app = headless_app();
+        build(&mut app, BuildingKind::Wall, IVec2::ZERO);
+        let wall = building_at(&app, IVec2::ZERO).unwrap();
+        let health = app.world.get::<Health>(wall).unwrap();
+        assert!(health.current < health.max);
+
+        // Skip the wait instead of sleeping through it
+        let mut construction = app.world.get_mut::<UnderConstruction>(wall).unwrap();
+        let build_time = construction.timer.duration();
+        construction.timer.tick(build_time);
+        let bar = construction.bar;
+        app.update();
+
+        assert!(app.world.get::<UnderConstruction>(wall).is_none());
+        assert!(app.world.get_entity(bar).is_none());
+    }
+
+    #[test]
+    fn selling_refunds_once_and_frees_the_tile() {
+        let mut app = headless_app();
+        build(&mut app, BuildingKind::Wall, IVec2::ZERO);
+        let wall = building_at(&app, IVec2::ZERO).unwrap();
+        let refund = app
+            .world
+            .get::<Building>(wall)
+            .unwrap()
+            .refund(&RefundRate::default());
+        assert_eq!(refund.len(), 1);
+        let (kind, amount) = refund[0];
+        let before = stock(&app, kind);
+
+        // Selling the same building twice in a frame only pays once
+        for _ in 0..2 {
+            send(
+                &mut app,
+                SellRequest {
+                    building: wall,
+                    player: None,
+                },
+            );
+        }
+        app.update();
+
+        assert_eq!(count_events::<ActionRejected>(&app), 1);
+        assert_eq!(stock(&app, kind), before + amount);
+        assert!(app.world.get_entity(wall).is_none());
+        assert!(building_at(&app, IVec2::ZERO).is_none());
+    }
+
+    #[test]
+    fn upgrading_pays_and_applies_the_stat() {
+        let mut app = headless_app();
+        build(&mut app, BuildingKind::Wall, IVec2::ZERO);
+        let wall = building_at(&app, IVec2::ZERO).unwrap();
+        app.world.entity_mut(wall).remove::<UnderConstruction>();
+        let gold = stock(&app, ResourceKind::Gold);
+        let max_health = app.world.get::<Health>(wall).unwrap().max;
+        upgrade(&mut app, wall);
+
+        assert_eq!(count_events::<BuildingUpgraded>(&app), 1);
+        let building = app.world.get::<Building>(wall).unwrap();
+        assert_eq!(building.tier, 1);
+        assert_eq!(building.upgrades, vec![0]);
+        let cost = WALL_DEF.upgrades[0].cost_at(0);
+        assert_eq!(stock(&app, ResourceKind::Gold), gold - cost);
+        assert_eq!(
+            app.world.get::<Health>(wall).unwrap().max,
+            max_health * WALL_DEF.upgrades[0].factor
+        );
+    }
+
+    #[test]
+    fn upgrading_under_construction_is_rejected() {
+        let mut app = headless_app();
+        build(&mut app, BuildingKind::Wall, IVec2::ZERO);
+        let wall = building_at(&app, IVec2::ZERO).unwrap();
+        let gold = stock(&app, ResourceKind::Gold);
+        upgrade(&mut app, wall);
+
+        assert_eq!(count_events::<ActionRejected>(&app), 1);
+        assert_eq!(app.world.get::<Building>(wall).unwrap().tier, 0);
+        assert_eq!(stock(&app, ResourceKind::Gold), gold);
+    }
+
+    #[test]
+    fn upgrading_past_the_max_tier_is_rejected() {
+        let mut app = headless_app();
+        build(&mut app, BuildingKind::Wall, IVec2::ZERO);
+        let wall = building_at(&app, IVec2::ZERO).unwrap();
+        app.world.entity_mut(wall).remove::<UnderConstruction>();
+        for _ in 0..WALL_DEF.max_tier {
+            upgrade(&mut app, wall);
+        }
+        let gold = stock(&app, ResourceKind::Gold);
+        upgrade(&mut app, wall);
+
+        assert_eq!(count_events::<ActionRejected>(&app), 1);
+        assert_eq!(
+            app.world.get::<Building>(wall).unwrap().tier,
+            WALL_DEF.max_tier
+        );
+        assert_eq!(stock(&app, ResourceKind::Gold), gold);
     }
 }
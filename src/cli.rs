@@ -0,0 +1,180 @@
+//! Command line parsing. Options not given on the command line come from the config file,
+//! then from the defaults in `constants`.
+
+use std::{
+    fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+
+use clap::{Args, CommandFactory, ErrorKind, Parser, Subcommand};
+use serde::Deserialize;
+
+use crate::{
+    constants::*,
+    networking::{address_has_port, parse_address, protocol::validate_username, NetworkRole},
+};
+
+#[derive(Parser)]
+#[clap(version, about = "Base defense")]
+struct Cli {
+    /// Defaults to hosting a game
+    #[clap(subcommand)]
+    mode: Option<Mode>,
+    /// Seed for the map and the simulation [default: 0]
+    #[clap(long, global = true)]
+    seed: Option<u64>,
+    /// Width and height of the map in tiles, an even number [default: 100]
+    #[clap(long, global = true)]
+    map_size: Option<i32>,
+    /// RON file with defaults for any of these options
+    #[clap(long, short, global = true, value_name = "FILE")]
+    config: Option<PathBuf>,
+}
+
+#[derive(Subcommand)]
+enum Mode {
+    /// Play alone, without networking
+    Singleplayer,
+    /// Host a game and play in it
+    Server(ServerArgs),
+    /// Host a game without opening a window
+    HeadlessServer(ServerArgs),
+    /// Join a game hosted elsewhere
+    Client(ClientArgs),
+}
+
+#[derive(Args, Default)]
+struct ServerArgs {
+    /// Port to listen on, unless the address includes one [default: 5000]
+    #[clap(long, short)]
+    port: Option<u16>,
+    /// Address clients use to reach this server, as HOST or HOST:PORT [default: 127.0.0.1]
+    #[clap(long, short)]
+    address: Option<String>,
+}
+
+#[derive(Args)]
+struct ClientArgs {
+    /// Server to connect to, as HOST or HOST:PORT [default: 127.0.0.1]
+    #[clap(long, short)]
+    address: Option<String>,
+    /// Server port, unless the address includes one [default: 5000]
+    #[clap(long, short)]
+    port: Option<u16>,
+    /// Name shown to other players [default: player]
+    #[clap(long, short)]
+    username: Option<String>,
+}
+
+/// Contents of the `--config` file, e.g. `(address: "192.168.1.10", seed: 42)`.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    address: String,
+    port: u16,
+    username: String,
+    seed: u64,
+    map_size: i32,
+}
+
+impl Default for ConfigFile {
+    fn default() -> Self {
+        ConfigFile {
+            address: DEFAULT_ADDRESS.to_string(),
+            port: DEFAULT_PORT,
+            username: DEFAULT_USERNAME.to_string(),
+            seed: DEFAULT_SEED,
+            map_size: MAP_SIZE,
+        }
+    }
+}
+
+/// Everything the command line decides about this run.
+pub struct Options {
+    pub role: NetworkRole,
+    /// Run the simulation only, without a window or rendering.
+    pub headless: bool,
+    pub server_addr: SocketAddr,
+    pub username: String,
+    pub seed: u64,
+    pub map_size: i32,
+}
+
+impl Options {
+    /// Parses the command line, printing help or an error and exiting when it isn't usable.
+    pub fn parse() -> Options {
+        let cli = Cli::parse();
+        let config = match &cli.config {
+            Some(path) => read_config(path).unwrap_or_else(|err| exit_with(ErrorKind::Io, err)),
+            None => ConfigFile::default(),
+        };
+
+        let map_size = cli.map_size.unwrap_or(config.map_size);
+        // The map is laid out symmetrically around the origin, half the size on each side
+        if !(MIN_MAP_SIZE..=MAX_MAP_SIZE).contains(&map_size) || map_size % 2 != 0 {
+            exit_with(
+                ErrorKind::ValueValidation,
+                format!(
+                    "map size must be an even number between {} and {}, got {}",
+                    MIN_MAP_SIZE, MAX_MAP_SIZE, map_size
+                ),
+            );
+        }
+
+        let (role, headless, address, port, username) = match cli
+            .mode
+            .unwrap_or_else(|| Mode::Server(ServerArgs::default()))
+        {
+            Mode::Singleplayer => (NetworkRole::Singleplayer, false, None, None, None),
+            Mode::Server(args) => (NetworkRole::Server, false, args.address, args.port, None),
+            Mode::HeadlessServer(args) => {
+                (NetworkRole::Server, true, args.address, args.port, None)
+            }
+            Mode::Client(args) => (
+                NetworkRole::Client,
+                false,
+                args.address,
+                args.port,
+                args.username,
+            ),
+        };
+        let address = address.unwrap_or(config.address);
+        if port.is_some() && address_has_port(&address) {
+            exit_with(
+                ErrorKind::ArgumentConflict,
+                format!(
+                    "--port can't be used when the address \"{}\" already includes a port",
+                    address
+                ),
+            );
+        }
+        let server_addr = parse_address(&address, port.unwrap_or(config.port))
+            .unwrap_or_else(|err| exit_with(ErrorKind::ValueValidation, err));
+
+        let username = username.unwrap_or(config.username);
+        if let Err(reason) = validate_username(&username) {
+            exit_with(ErrorKind::ValueValidation, reason);
+        }
+
+        Options {
+            role,
+            headless,
+            server_addr,
+            username,
+            seed: cli.seed.unwrap_or(config.seed),
+            map_size,
+        }
+    }
+}
+
+fn read_config(path: &Path) -> Result<ConfigFile, String> {
+    let text = fs::read_to_string(path)
+        .map_err(|err| format!("could not read {}: {}", path.display(), err))?;
+    ron::from_str(&text).map_err(|err| format!("invalid config {}: {}", path.display(), err))
+}
+
+/// Reports the problem the way clap reports its own errors, with a usage line.
+fn exit_with(kind: ErrorKind, message: impl std::fmt::Display) -> ! {
+    Cli::command().error(kind, message).exit()
+}
@@ -0,0 +1,218 @@
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    constants::*,
+    economy::{Amount, ResourceKind},
+    hp_bar::DamageDealt,
+};
+
+/// Floating numbers for the damage and reward events the simulation sends. The events
+/// themselves are registered by `HealthPlugin` and `EconomyPlugin`, clients get them
+/// replicated from the server.
+pub struct CombatTextPlugin;
+
+/// What dealt a hit, used to colour its damage number.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum DamageKind {
+    Melee,
+    Projectile,
+}
+
+/// Sent whenever something takes a hit.
+pub struct DamageEvent {
+    pub position: Vec3,
+    pub dealt: DamageDealt,
+    pub kind: DamageKind,
+    pub crit: bool,
+}
+
+/// Sent whenever a building hands resources to the player.
+pub struct RewardEvent {
+    pub position: Vec3,
+    pub resource: ResourceKind,
+    pub amount: Amount,
+}
+
+#[derive(Component)]
+struct FloatingText {
+    timer: Timer,
+    color: Color,
+}
+
+/// Hidden text entities waiting to be reused, so busy waves don't
+/// spawn and despawn hundreds of entities per second.
+#[derive(Default)]
+struct CombatTextPool {
+    free: Vec<Entity>,
+    active: usize,
+}
+
+struct CombatTextFont(Handle<Font>);
+
+impl Plugin for CombatTextPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<CombatTextPool>()
+            .add_startup_system(load_font)
+            .add_system(spawn_damage_text)
+            .add_system(spawn_reward_text)
+            .add_system(update_floating_text);
+    }
+}
+
+fn load_font(mut commands: Commands, asset_server: Res<AssetServer>) {
+    commands.insert_resource(CombatTextFont(asset_server.load("fonts/FiraSans-Bold.ttf")));
+}
+
+fn spawn_damage_text(
+    mut commands: Commands,
+    mut events: EventReader<DamageEvent>,
+    mut pool: ResMut<CombatTextPool>,
+    mut q_text: Query<(
+        &mut Text,
+        &mut Transform,
+        &mut Visibility,
+        &mut FloatingText,
+    )>,
+    font: Res<CombatTextFont>,
+) {
+    for event in events.iter() {
+        let (value, color) = if event.dealt.health > 0.0 {
+            let color = match event.kind {
+                DamageKind::Melee => COLOR_DAMAGE_MELEE,
+                DamageKind::Projectile => COLOR_DAMAGE_PROJECTILE,
+            };
+            (format!("{:.0}", event.dealt.health), color)
+        } else if event.dealt.shield > 0.0 {
+            (format!("{:.0}", event.dealt.shield), COLOR_SHIELD_BAR)
+        } else {
+            ("Immune".to_string(), COLOR_HP_BAR_INVULNERABLE)
+        };
+        let (value, size) = if event.crit {
+            (format!("{}!", value), COMBAT_TEXT_SIZE * 1.5)
+        } else {
+            (value, COMBAT_TEXT_SIZE)
+        };
+        show_text(
+            &mut commands,
+            &mut pool,
+            &mut q_text,
+            &font,
+            event.position,
+            value,
+            color,
+            size,
+        );
+    }
+}
+
+fn spawn_reward_text(
+    mut commands: Commands,
+    mut events: EventReader<RewardEvent>,
+    mut pool: ResMut<CombatTextPool>,
+    mut q_text: Query<(
+        &mut Text,
+        &mut Transform,
+        &mut Visibility,
+        &mut FloatingText,
+    )>,
+    font: Res<CombatTextFont>,
+) {
+    for event in events.iter() {
+        show_text(
+            &mut commands,
+            &mut pool,
+            &mut q_text,
+            &font,
+            event.position,
+            format!("+{} {}", event.amount, event.resource.name()),
+            event.resource.color(),
+            COMBAT_TEXT_SIZE * 0.8,
+        );
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn show_text(
+    commands: &mut Commands,
+    pool: &mut CombatTextPool,
+    q_text: &mut Query<(
+        &mut Text,
+        &mut Transform,
+        &mut Visibility,
+        &mut FloatingText,
+    )>,
+    font: &CombatTextFont,
+    position: Vec3,
+    value: String,
+    color: Color,
+    size: f32,
+) {
+    let translation = position.truncate().extend(COMBAT_TEXT_LAYER) + Vec3::Y * TILE_SIZE * 0.3;
+    let style = TextStyle {
+        font: font.0.clone(),
+        font_size: size,
+        color,
+    };
+    let floating = FloatingText {
+        timer: Timer::from_seconds(COMBAT_TEXT_LIFETIME, false),
+        color,
+    };
+
+    if let Some(entity) = pool.free.pop() {
+        if let Ok((mut text, mut transform, mut visibility, mut state)) = q_text.get_mut(entity) {
+            text.sections[0].value = value;
+            text.sections[0].style = style;
+            transform.translation = translation;
+            visibility.is_visible = true;
+            *state = floating;
+            pool.active += 1;
+            return;
+        }
+    }
+    if pool.active >= COMBAT_TEXT_LIMIT {
+        return;
+    }
+    pool.active += 1;
+    commands
+        .spawn_bundle(Text2dBundle {
+            text: Text::from_section(value, style).with_alignment(TextAlignment::CENTER),
+            transform: Transform {
+                translation,
+                scale: Vec3::splat(COMBAT_TEXT_SCALE),
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+        .insert(floating)
+        .insert(Name::new("Combat text"));
+}
+
+fn update_floating_text(
+    mut pool: ResMut<CombatTextPool>,
+    mut q_text: Query<(
+        Entity,
+        &mut Text,
+        &mut Transform,
+        &mut Visibility,
+        &mut FloatingText,
+    )>,
+    time: Res<Time>,
+) {
+    for (entity, mut text, mut transform, mut visibility, mut floating) in q_text.iter_mut() {
+        if !visibility.is_visible {
+            continue;
+        }
+        floating.timer.tick(time.delta());
+        if floating.timer.just_finished() {
+            visibility.is_visible = false;
+            pool.active -= 1;
+            pool.free.push(entity);
+            continue;
+        }
+        transform.translation.y += COMBAT_TEXT_RISE_SPEED * time.delta_seconds();
+        let mut color = floating.color;
+        color.set_a(1.0 - floating.timer.percent());
+        text.sections[0].style.color = color;
+    }
+}
@@ -1,15 +1,134 @@
 use bevy::prelude::Color;
 
+use crate::economy::{Amount, ResourceAmount, ResourceKind};
+
 pub const RESOLUTION: f32 = 16.0 / 9.0;
 pub const TILE_SIZE: f32 = 0.15;
 
+// Map width and height in tiles, unless set on the command line
 pub const MAP_SIZE: i32 = 100;
+pub const MIN_MAP_SIZE: i32 = 10;
+pub const MAX_MAP_SIZE: i32 = 1000;
 // Bound size used by noise generation
 pub const BOUND_SIZE: f64 = 8.0;
 
+pub const BUILDING_LAYER: f32 = 2.0;
 pub const PROJECTILE_LAYER: f32 = 20.0;
-pub const STARTING_GOLD: f32 = 100.0;
+pub const COMBAT_TEXT_LAYER: f32 = 30.0;
+pub const STARTING_RESOURCES: [ResourceAmount; 3] = [
+    (ResourceKind::Gold, Amount::whole(100)),
+    (ResourceKind::Stone, Amount::whole(20)),
+    (ResourceKind::Wood, Amount::whole(20)),
+];
+// Added to the shared stockpile the first time each player joins a hosted game
+pub const JOINING_PLAYER_RESOURCES: [ResourceAmount; 1] = [(ResourceKind::Gold, Amount::whole(50))];
+pub const SELL_REFUND_RATE: f32 = 0.5;
+// Health restored per second by a manual repair
+pub const REPAIR_RATE: f32 = 20.0;
+pub const REPAIR_COST_PER_HP: Amount = Amount::hundredths(20);
+// Fraction of max health a building starts with when construction begins
+pub const CONSTRUCTION_START_HEALTH: f32 = 0.2;
+// Seconds without taking damage before shields start recharging
+pub const SHIELD_REGEN_DELAY: f32 = 3.0;
+pub const TOWER_SHIELD: f32 = 30.0;
+pub const TOWER_SHIELD_REGEN: f32 = 5.0;
+pub const WALL_REGEN: f32 = 1.0;
+pub const MELEE_ENEMY_REGEN: f32 = 2.0;
+pub const RANGED_ENEMY_SHIELD: f32 = 20.0;
+pub const RANGED_ENEMY_SHIELD_REGEN: f32 = 8.0;
+// Seed used when none is given, so runs are reproducible by default
+pub const DEFAULT_SEED: u64 = 0;
+pub const TOWER_CRIT_CHANCE: f32 = 0.1;
+pub const TOWER_CRIT_MULTIPLIER: f32 = 2.0;
+pub const TOWER_DAMAGE_VARIANCE: f32 = 0.1;
+pub const MELEE_ENEMY_DAMAGE_VARIANCE: f32 = 0.2;
+pub const RANGED_ENEMY_CRIT_CHANCE: f32 = 0.05;
+pub const RANGED_ENEMY_CRIT_MULTIPLIER: f32 = 1.5;
+// Spent projectiles kept around for reuse
+pub const PROJECTILE_POOL_LIMIT: usize = 1000;
+// Port used when none is given on the command line
+pub const DEFAULT_PORT: u16 = 5000;
+// Where clients look for the server when no address is given
+pub const DEFAULT_ADDRESS: &str = "127.0.0.1";
+pub const DEFAULT_USERNAME: &str = "player";
+pub const MAX_USERNAME_LENGTH: usize = 32;
+// Where a client keeps the id that tells the server it's the same player after reconnecting
+pub const PLAYER_ID_FILE: &str = "player_id";
+// Longest chat text in bytes, so a relayed line with its sender's name fits a reliable message
+pub const MAX_CHAT_BYTES: usize = 512;
+// Simulation updates per second on a headless server
+pub const SERVER_TICK_RATE: f64 = 60.0;
+// Snapshots sent per second
+pub const SNAPSHOT_RATE: f32 = 20.0;
+// Clients draw replicated entities this many seconds in the past
+pub const INTERPOLATION_DELAY: f32 = 0.1;
+// Seconds an entity keeps moving on its own after the last snapshot
+pub const MAX_EXTRAPOLATION: f32 = 0.25;
+// Share of the difference to the server clock removed with every new snapshot
+pub const CLOCK_CORRECTION: f64 = 0.1;
+// Seconds the client clock may be off before it jumps instead of drifting back
+pub const CLOCK_SNAP_THRESHOLD: f64 = 1.0;
+// Snapshots kept per entity for interpolation
+pub const SNAPSHOT_BUFFER_SIZE: usize = 32;
+// Recent snapshots kept on both sides to compute and resolve deltas against
+pub const SNAPSHOT_HISTORY_SIZE: usize = 32;
+// Most entities put in one snapshot message
+pub const SNAPSHOT_CHUNK_SIZE: usize = 128;
+// Most messages one snapshot is split into, so a client never reserves room for more
+pub const MAX_SNAPSHOT_PARTS: u16 = 64;
+// Most floating numbers put in one combat text message
+pub const COMBAT_TEXT_CHUNK_SIZE: usize = 128;
+// Bytes each channel may put in a packet, and the largest message it accepts
+pub const RELIABLE_PACKET_BUDGET: u64 = 4000;
+pub const RELIABLE_MAX_MESSAGE_SIZE: u64 = 1200;
+pub const UNRELIABLE_PACKET_BUDGET: u64 = 7000;
+pub const UNRELIABLE_MAX_MESSAGE_SIZE: u64 = 6000;
+pub const BLOCK_PACKET_BUDGET: u64 = 4000;
+pub const BLOCK_MAX_MESSAGE_SIZE: u64 = 256 * 1024;
+// Seconds between bandwidth reports on the server
+pub const BANDWIDTH_LOG_INTERVAL: f32 = 5.0;
+// Floating combat text
+pub const COMBAT_TEXT_SIZE: f32 = 40.0;
+// Text is laid out in pixels, so it gets scaled down to world units
+pub const COMBAT_TEXT_SCALE: f32 = 0.003;
+pub const COMBAT_TEXT_LIFETIME: f32 = 0.8;
+pub const COMBAT_TEXT_RISE_SPEED: f32 = TILE_SIZE;
+// Cap on simultaneously visible popups
+pub const COMBAT_TEXT_LIMIT: usize = 200;
+// Seconds freshly spawned enemies ignore damage for
+pub const ENEMY_SPAWN_INVULNERABILITY: f32 = 1.0;
+
+pub const TOWER_POWER_DEMAND: f32 = 5.0;
+pub const GENERATOR_OUTPUT: f32 = 12.0;
+// Power network reach, in tiles
+pub const GENERATOR_RADIUS: i32 = 2;
+pub const PYLON_RADIUS: i32 = 4;
 
 pub const COLOR_TOWER: Color = Color::rgb(0.8, 0.2, 0.2);
 pub const COLOR_ENEMY: Color = Color::rgb(0.2, 0.8, 0.2);
+pub const COLOR_RANGED_ENEMY: Color = Color::rgb(0.6, 0.8, 0.1);
 pub const COLOR_MINER: Color = Color::rgb(0.3, 0.2, 0.5);
+pub const COLOR_WALL: Color = Color::rgb(0.45, 0.4, 0.35);
+pub const COLOR_REPAIR_STATION: Color = Color::rgb(0.2, 0.5, 0.8);
+pub const COLOR_QUARRY: Color = Color::rgb(0.55, 0.55, 0.6);
+pub const COLOR_LUMBERYARD: Color = Color::rgb(0.5, 0.35, 0.15);
+pub const COLOR_ORE_MINE: Color = Color::rgb(0.6, 0.3, 0.25);
+pub const COLOR_SMELTER: Color = Color::rgb(0.85, 0.45, 0.1);
+pub const COLOR_GENERATOR: Color = Color::rgb(0.95, 0.9, 0.2);
+pub const COLOR_PYLON: Color = Color::rgb(0.4, 0.8, 0.9);
+
+pub const COLOR_GOLD: Color = Color::rgb(1.0, 0.8, 0.0);
+pub const COLOR_STONE: Color = Color::rgb(0.5, 0.5, 0.55);
+pub const COLOR_WOOD: Color = Color::rgb(0.1, 0.45, 0.1);
+pub const COLOR_ORE: Color = Color::rgb(0.6, 0.3, 0.2);
+pub const COLOR_METAL: Color = Color::rgb(0.7, 0.75, 0.8);
+pub const COLOR_PLAYER_PROJECTILE: Color = Color::rgb(0.2, 0.2, 0.8);
+pub const COLOR_ENEMY_PROJECTILE: Color = Color::rgb(0.9, 0.5, 0.1);
+pub const COLOR_HP_BAR: Color = Color::rgb(0.1, 0.9, 0.1);
+pub const COLOR_HP_BAR_LOW: Color = Color::rgb(0.9, 0.1, 0.1);
+pub const COLOR_HP_BAR_REPAIRING: Color = Color::rgb(0.2, 0.7, 1.0);
+pub const COLOR_HP_BAR_INVULNERABLE: Color = Color::rgb(1.0, 0.95, 0.6);
+pub const COLOR_SHIELD_BAR: Color = Color::rgb(0.4, 0.6, 1.0);
+pub const COLOR_DAMAGE_MELEE: Color = Color::rgb(1.0, 0.4, 0.2);
+pub const COLOR_DAMAGE_PROJECTILE: Color = Color::rgb(1.0, 1.0, 1.0);
+pub const COLOR_CONSTRUCTION_BAR: Color = Color::rgb(0.9, 0.6, 0.1);
@@ -0,0 +1,442 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt,
+    iter::Sum,
+    ops::{Add, AddAssign, Div, Mul, Neg, Sub, SubAssign},
+};
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    building::BuildingKind,
+    combat_text::RewardEvent,
+    constants::*,
+    networking::{run_if_authority, PlayerJoined},
+};
+
+pub struct EconomyPlugin;
+
+impl Plugin for EconomyPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<PlayerResources>()
+            .add_event::<RewardEvent>()
+            .add_system(update_ledger)
+            .add_system(grant_joining_resources.with_run_criteria(run_if_authority));
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+pub enum ResourceKind {
+    Gold,
+    Stone,
+    Wood,
+    Ore,
+    /// Refined from ore, there are no metal deposits.
+    Metal,
+}
+
+impl ResourceKind {
+    pub const ALL: [ResourceKind; 5] = [
+        ResourceKind::Gold,
+        ResourceKind::Stone,
+        ResourceKind::Wood,
+        ResourceKind::Ore,
+        ResourceKind::Metal,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ResourceKind::Gold => "gold",
+            ResourceKind::Stone => "stone",
+            ResourceKind::Wood => "wood",
+            ResourceKind::Ore => "ore",
+            ResourceKind::Metal => "metal",
+        }
+    }
+
+    pub fn color(self) -> Color {
+        match self {
+            ResourceKind::Gold => COLOR_GOLD,
+            ResourceKind::Stone => COLOR_STONE,
+            ResourceKind::Wood => COLOR_WOOD,
+            ResourceKind::Ore => COLOR_ORE,
+            ResourceKind::Metal => COLOR_METAL,
+        }
+    }
+}
+
+/// Resource quantity in fixed point, counted in hundredths of a unit. Sums are exact, so the
+/// economy never drifts and comes out the same on the server and every client.
+#[derive(
+    Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Default, Serialize, Deserialize,
+)]
+pub struct Amount(pub i64);
+
+impl Amount {
+    pub const ZERO: Amount = Amount(0);
+    const SCALE: i64 = 100;
+
+    pub const fn whole(units: i64) -> Amount {
+        Amount(units * Self::SCALE)
+    }
+
+    pub const fn hundredths(hundredths: i64) -> Amount {
+        Amount(hundredths)
+    }
+
+    /// Multiplies by a tuning factor such as an upgrade bonus, rounding to the nearest
+    /// hundredth.
+    pub fn scale(self, factor: f32) -> Amount {
+        Amount((self.0 as f64 * factor as f64).round() as i64)
+    }
+
+    pub fn as_f32(self) -> f32 {
+        self.0 as f32 / Self::SCALE as f32
+    }
+}
+
+/// Shows whole units only, the fraction is an implementation detail.
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0 / Self::SCALE)
+    }
+}
+
+impl Add for Amount {
+    type Output = Amount;
+
+    fn add(self, rhs: Amount) -> Amount {
+        Amount(self.0 + rhs.0)
+    }
+}
+
+impl Sub for Amount {
+    type Output = Amount;
+
+    fn sub(self, rhs: Amount) -> Amount {
+        Amount(self.0 - rhs.0)
+    }
+}
+
+impl AddAssign for Amount {
+    fn add_assign(&mut self, rhs: Amount) {
+        self.0 += rhs.0;
+    }
+}
+
+impl SubAssign for Amount {
+    fn sub_assign(&mut self, rhs: Amount) {
+        self.0 -= rhs.0;
+    }
+}
+
+impl Neg for Amount {
+    type Output = Amount;
+
+    fn neg(self) -> Amount {
+        Amount(-self.0)
+    }
+}
+
+impl Mul<i64> for Amount {
+    type Output = Amount;
+
+    fn mul(self, rhs: i64) -> Amount {
+        Amount(self.0 * rhs)
+    }
+}
+
+/// Rounds towards zero, to the hundredth.
+impl Div<i64> for Amount {
+    type Output = Amount;
+
+    fn div(self, rhs: i64) -> Amount {
+        Amount(self.0 / rhs)
+    }
+}
+
+impl Sum for Amount {
+    fn sum<I: Iterator<Item = Amount>>(iter: I) -> Amount {
+        iter.fold(Amount::ZERO, Add::add)
+    }
+}
+
+/// Some amount of a single resource, costs are written as lists of these.
+pub type ResourceAmount = (ResourceKind, Amount);
+
+pub fn format_amounts(amounts: &[ResourceAmount]) -> String {
+    amounts
+        .iter()
+        .map(|(kind, amount)| format!("{} {}", amount, kind.name()))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Amounts of any number of resources.
+#[derive(Default, Clone)]
+pub struct Inventory {
+    amounts: HashMap<ResourceKind, Amount>,
+}
+
+impl Inventory {
+    pub fn new(amounts: &[ResourceAmount]) -> Inventory {
+        let mut inventory = Inventory::default();
+        inventory.add_all(amounts);
+        inventory
+    }
+
+    pub fn get(&self, kind: ResourceKind) -> Amount {
+        self.amounts.get(&kind).copied().unwrap_or_default()
+    }
+
+    pub fn add(&mut self, kind: ResourceKind, amount: Amount) {
+        *self.amounts.entry(kind).or_default() += amount;
+    }
+
+    pub fn add_all(&mut self, amounts: &[ResourceAmount]) {
+        for &(kind, amount) in amounts {
+            self.add(kind, amount);
+        }
+    }
+
+    pub fn can_afford(&self, cost: &[ResourceAmount]) -> bool {
+        cost.iter().all(|&(kind, amount)| self.get(kind) >= amount)
+    }
+
+    /// Non-zero amounts, in `ResourceKind::ALL` order.
+    pub fn amounts(&self) -> Vec<ResourceAmount> {
+        ResourceKind::ALL
+            .iter()
+            .map(|&kind| (kind, self.get(kind)))
+            .filter(|&(_, amount)| amount != Amount::ZERO)
+            .collect()
+    }
+}
+
+/// Reason a resource was gained or spent.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+pub enum LedgerSource {
+    Starting,
+    /// Given when another player joins a hosted game.
+    PlayerJoined,
+    Production(BuildingKind),
+    /// Inputs consumed by a refining building.
+    Refining(BuildingKind),
+    Construction,
+    Upgrade,
+    Repair,
+    Sale,
+}
+
+impl LedgerSource {
+    pub fn label(self) -> String {
+        match self {
+            LedgerSource::Starting => "Starting resources".to_string(),
+            LedgerSource::PlayerJoined => "Joining players".to_string(),
+            LedgerSource::Production(kind) => kind.def().name.to_string(),
+            LedgerSource::Refining(kind) => format!("{} inputs", kind.def().name),
+            LedgerSource::Construction => "Construction".to_string(),
+            LedgerSource::Upgrade => "Upgrades".to_string(),
+            LedgerSource::Repair => "Repairs".to_string(),
+            LedgerSource::Sale => "Sold buildings".to_string(),
+        }
+    }
+}
+
+type LedgerEntries = HashMap<(LedgerSource, ResourceKind), Amount>;
+
+/// Net change of one resource from one source over the last minute.
+pub type Income = (LedgerSource, ResourceKind, Amount);
+
+/// Record of every income and expense. Changes are grouped in one second buckets so that the
+/// last minute can be summed up by source.
+pub struct Ledger {
+    current: LedgerEntries,
+    buckets: VecDeque<LedgerEntries>,
+    bucket_timer: Timer,
+    pub totals: LedgerEntries,
+    /// The server's summary, on clients that only see the resulting stock.
+    replicated: Option<Vec<Income>>,
+}
+
+impl Default for Ledger {
+    fn default() -> Self {
+        Ledger {
+            current: HashMap::new(),
+            buckets: VecDeque::new(),
+            bucket_timer: Timer::from_seconds(1.0, true),
+            totals: HashMap::new(),
+            replicated: None,
+        }
+    }
+}
+
+impl Ledger {
+    const BUCKETS_PER_MINUTE: usize = 60;
+
+    fn record(&mut self, source: LedgerSource, kind: ResourceKind, amount: Amount) {
+        *self.current.entry((source, kind)).or_default() += amount;
+        *self.totals.entry((source, kind)).or_default() += amount;
+    }
+
+    fn roll(&mut self) {
+        self.buckets.push_back(std::mem::take(&mut self.current));
+        while self.buckets.len() > Self::BUCKETS_PER_MINUTE {
+            self.buckets.pop_front();
+        }
+    }
+
+    /// True for the frame a new bucket started, the only time `per_minute` changes.
+    pub fn rolled(&self) -> bool {
+        self.bucket_timer.just_finished()
+    }
+
+    /// Net change per source and resource over the last minute, sorted by source then
+    /// resource. Extrapolated if the game has been running for less than a minute.
+    pub fn per_minute(&self) -> Vec<Income> {
+        if let Some(income) = &self.replicated {
+            return income.clone();
+        }
+        let mut sums: LedgerEntries = HashMap::new();
+        for bucket in self.buckets.iter() {
+            for (&key, &amount) in bucket.iter() {
+                *sums.entry(key).or_default() += amount;
+            }
+        }
+        // Multiply before dividing so a partly filled minute isn't rounded down to 1x
+        let filled = self.buckets.len().max(1) as i64;
+        let mut entries: Vec<_> = sums
+            .into_iter()
+            .filter(|&(_, amount)| amount != Amount::ZERO)
+            .map(|((source, kind), amount)| {
+                let per_minute = amount * Self::BUCKETS_PER_MINUTE as i64 / filled;
+                (source, kind, per_minute)
+            })
+            .collect();
+        entries.sort_by_key(|(source, kind, _)| (source.label(), kind.name()));
+        entries
+    }
+
+    /// Shows the server's `per_minute` from now on instead of what was recorded here.
+    pub fn set_per_minute(&mut self, income: Vec<Income>) {
+        self.replicated = Some(income);
+    }
+}
+
+/// Everything the player has stockpiled. All changes go through here so they end up in the
+/// ledger.
+pub struct PlayerResources {
+    inventory: Inventory,
+    pub ledger: Ledger,
+}
+
+impl Default for PlayerResources {
+    fn default() -> Self {
+        let mut res = PlayerResources {
+            inventory: Inventory::default(),
+            ledger: Ledger::default(),
+        };
+        res.earn_all(&STARTING_RESOURCES, LedgerSource::Starting);
+        res
+    }
+}
+
+impl PlayerResources {
+    pub fn get(&self, kind: ResourceKind) -> Amount {
+        self.inventory.get(kind)
+    }
+
+    pub fn can_afford(&self, cost: &[ResourceAmount]) -> bool {
+        self.inventory.can_afford(cost)
+    }
+
+    pub fn amounts(&self) -> Vec<ResourceAmount> {
+        self.inventory.amounts()
+    }
+
+    /// Overwrites the stock with the server's, without touching the ledger. Its summary is
+    /// replicated separately through `Ledger::set_per_minute`.
+    pub fn set_amounts(&mut self, amounts: &[ResourceAmount]) {
+        self.inventory = Inventory::new(amounts);
+    }
+
+    pub fn earn(&mut self, kind: ResourceKind, amount: Amount, source: LedgerSource) {
+        self.inventory.add(kind, amount);
+        self.ledger.record(source, kind, amount);
+    }
+
+    pub fn earn_all(&mut self, amounts: &[ResourceAmount], source: LedgerSource) {
+        for &(kind, amount) in amounts {
+            self.earn(kind, amount, source);
+        }
+    }
+
+    /// Takes `cost` out of the stockpile. Nothing is taken if it can't all be paid.
+    pub fn spend(&mut self, cost: &[ResourceAmount], source: LedgerSource) -> bool {
+        if !self.can_afford(cost) {
+            return false;
+        }
+        for &(kind, amount) in cost {
+            self.earn(kind, -amount, source);
+        }
+        true
+    }
+}
+
+/// Each player is only welcomed once, or reconnecting, even under another name, would mint
+/// resources.
+fn grant_joining_resources(
+    mut joined: EventReader<PlayerJoined>,
+    mut res: ResMut<PlayerResources>,
+    mut welcomed: Local<HashSet<u64>>,
+) {
+    for player in joined.iter() {
+        if welcomed.insert(player.player_id) {
+            res.earn_all(&JOINING_PLAYER_RESOURCES, LedgerSource::PlayerJoined);
+        }
+    }
+}
+
+fn update_ledger(mut res: ResMut<PlayerResources>, time: Res<Time>) {
+    res.ledger.bucket_timer.tick(time.delta());
+    for _ in 0..res.ledger.bucket_timer.times_finished() {
+        res.ledger.roll();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{headless_app, send};
+
+    fn join(app: &mut App, client_id: u64, name: &str, player_id: u64) -> Amount {
+        send(
+            app,
+            PlayerJoined {
+                client_id,
+                name: name.to_string(),
+                player_id,
+            },
+        );
+        app.update();
+        app.world
+            .resource::<PlayerResources>()
+            .get(ResourceKind::Gold)
+    }
+
+    #[test]
+    fn joining_resources_are_granted_once_per_player() {
+        let mut app = headless_app();
+        app.update();
+        let start = app
+            .world
+            .resource::<PlayerResources>()
+            .get(ResourceKind::Gold);
+        let (_, grant) = JOINING_PLAYER_RESOURCES[0];
+        assert_eq!(join(&mut app, 1, "first", 7), start + grant);
+        // Reconnecting under another name is still the same player
+        assert_eq!(join(&mut app, 2, "second", 7), start + grant);
+        assert_eq!(join(&mut app, 3, "second", 8), start + grant * 2);
+    }
+}
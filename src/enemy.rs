@@ -1,102 +1,271 @@
 use crate::constants::*;
 use bevy::{math::Vec3Swizzles, prelude::*};
+use serde::{Deserialize, Serialize};
 use std::cmp::Ordering;
 
 use crate::{
-    hp_bar::{create_hp_bar, Health},
+    assets::GameAssets,
+    building::Building,
+    combat_text::{DamageEvent, DamageKind},
+    hp_bar::{create_hp_bar, DamageRoll, Health},
+    lifecycle::Despawning,
+    map::{tile_to_world, world_to_tile, TileGrid},
+    networking::run_if_authority,
+    pathfinding::find_path,
+    projectile::{Faction, ProjectileSpawner},
+    rng::{GameRng, RngUser},
     tower::Tower,
 };
 
 pub struct EnemyPlugin;
 
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+pub enum EnemyKind {
+    /// Walks up to a building and hits it directly.
+    #[default]
+    Melee,
+    /// Stops at `attack_range` and fires projectiles.
+    Ranged,
+}
+
 #[derive(Component, Default, Reflect)]
 #[reflect(Component)]
 pub struct Enemy {
     speed: f32,
     attack: f32,
+    roll: DamageRoll,
+    attack_range: f32,
     timer: Timer,
+    #[reflect(ignore)]
+    kind: EnemyKind,
+    /// Remaining tiles to walk through, `None` if walls cut off every route to the target.
+    #[reflect(ignore)]
+    path: Option<Vec<IVec2>>,
+    #[reflect(ignore)]
+    path_target: Option<Entity>,
+    path_version: u32,
+}
+
+/// Spawn an enemy at `translation`.
+pub struct SpawnEnemyRequest {
+    pub kind: EnemyKind,
+    pub translation: Vec3,
 }
 
 impl Plugin for EnemyPlugin {
     fn build(&self, app: &mut App) {
-        app.add_system(update_enemies);
+        app.add_event::<SpawnEnemyRequest>().add_system_set(
+            SystemSet::new()
+                .with_run_criteria(run_if_authority)
+                .with_system(spawn_requested_enemies)
+                .with_system(
+                    update_enemies
+                        .label(RngUser::Enemies)
+                        .after(RngUser::Towers),
+                ),
+        );
+    }
+}
+
+fn spawn_requested_enemies(
+    mut commands: Commands,
+    mut spawn_events: EventReader<SpawnEnemyRequest>,
+    assets: Res<GameAssets>,
+) {
+    for request in spawn_events.iter() {
+        Enemy::new(&mut commands, request.translation, request.kind, &assets);
     }
 }
 
 fn update_enemies(
     mut commands: Commands,
-    mut q_towers: Query<(Entity, &mut Health, &mut Tower, &mut Transform), Without<Enemy>>,
-    mut q_enemies: Query<(Entity, &mut Health, &mut Enemy, &mut Transform), Without<Tower>>,
+    mut q_buildings: Query<
+        (Entity, &mut Health, &Transform, Option<&Tower>),
+        (With<Building>, Without<Enemy>, Without<Despawning>),
+    >,
+    mut q_enemies: Query<
+        (Entity, &Health, &mut Enemy, &mut Transform),
+        (Without<Building>, Without<Despawning>),
+    >,
+    mut damage_events: EventWriter<DamageEvent>,
+    mut rng: ResMut<GameRng>,
+    grid: Res<TileGrid>,
     time: Res<Time>,
+    mut projectiles: ProjectileSpawner,
 ) {
     for (entity, health, mut enemy, mut transform) in q_enemies.iter_mut() {
         if health.current <= 0.0 {
-            commands.entity(entity).despawn_recursive();
+            commands.entity(entity).insert(Despawning);
             continue;
         }
 
         let pos: Vec2 = transform.translation.xy();
-        let closest_tower = q_towers.iter_mut().min_by(|a, b| {
-            if (a.3.translation.xy() - pos).length_squared()
-                < (b.3.translation.xy() - pos).length_squared()
-            {
-                Ordering::Less
-            } else {
-                Ordering::Greater
+        let closest_tower = q_buildings
+            .iter()
+            .filter(|(_, _, _, tower)| tower.is_some())
+            .min_by(|a, b| {
+                if (a.2.translation.xy() - pos).length_squared()
+                    < (b.2.translation.xy() - pos).length_squared()
+                {
+                    Ordering::Less
+                } else {
+                    Ordering::Greater
+                }
+            })
+            .map(|(entity, _, transform, _)| (entity, transform.translation.xy()));
+        let (target, target_pos) = match closest_tower {
+            Some(x) => x,
+            None => continue,
+        };
+        let diff = target_pos - pos;
+
+        // What to attack this frame and in which direction, if anything is in reach.
+        let mut victim = None;
+        let mut heading = diff;
+        if diff.length() < enemy.attack_range {
+            victim = Some((target, diff));
+        } else {
+            if enemy.path_target != Some(target) || enemy.path_version != grid.version {
+                enemy.path = find_path(&grid, world_to_tile(pos), world_to_tile(target_pos));
+                enemy.path_target = Some(target);
+                enemy.path_version = grid.version;
+            }
+            match enemy.path.as_mut() {
+                Some(path) => {
+                    while let Some(&next) = path.first() {
+                        if (tile_to_world(next) - pos).length() < TILE_SIZE * 0.1 {
+                            path.remove(0);
+                        } else {
+                            break;
+                        }
+                    }
+                    if let Some(&next) = path.first() {
+                        heading = tile_to_world(next) - pos;
+                    }
+                }
+                None => {
+                    // Walled in, so head straight for the target and break through whatever
+                    // is in the way.
+                    let ahead = world_to_tile(pos + diff.normalize() * TILE_SIZE * 0.6);
+                    if let Some(obstacle) = grid.occupant(ahead) {
+                        if obstacle != target {
+                            victim = Some((obstacle, tile_to_world(ahead) - pos));
+                        }
+                    }
+                }
             }
-        });
-        if closest_tower.is_none() {
-            continue;
         }
-        let (_entity, mut health, _tower, target) = closest_tower.unwrap();
-        let diff = target.translation.xy() - pos;
-        if diff.length() < TILE_SIZE * 0.3 {
+
+        if let Some((victim, aim)) = victim {
             enemy.timer.tick(time.delta());
             if enemy.timer.just_finished() {
-                health.current -= enemy.attack;
+                match enemy.kind {
+                    EnemyKind::Melee => {
+                        if let Ok((_, mut health, victim_transform, _)) =
+                            q_buildings.get_mut(victim)
+                        {
+                            let hit = enemy.roll.roll(enemy.attack, &mut rng);
+                            damage_events.send(DamageEvent {
+                                position: victim_transform.translation,
+                                dealt: health.damage(hit.damage),
+                                kind: DamageKind::Melee,
+                                crit: hit.crit,
+                            });
+                        }
+                    }
+                    EnemyKind::Ranged => {
+                        projectiles.spawn(
+                            pos.extend(PROJECTILE_LAYER),
+                            aim.normalize(),
+                            enemy.roll.roll(enemy.attack, &mut rng),
+                            10.0 * TILE_SIZE,
+                            Faction::Enemy,
+                        );
+                    }
+                }
             }
             continue;
         }
-        let norm_target = diff.normalize().extend(1.0);
-        let movement = norm_target * enemy.speed * time.delta_seconds();
+        let movement = heading.normalize_or_zero().extend(0.0) * enemy.speed * time.delta_seconds();
         transform.translation += movement;
     }
 }
 
 impl Enemy {
-    pub fn new(mut commands: Commands, mut translation: Vec3, asset_server: Res<AssetServer>) {
+    pub fn kind(&self) -> EnemyKind {
+        self.kind
+    }
+
+    pub fn new(
+        commands: &mut Commands,
+        mut translation: Vec3,
+        kind: EnemyKind,
+        assets: &GameAssets,
+    ) -> Entity {
         translation.z = 10.0;
+        let (enemy, color, mut health) = match kind {
+            EnemyKind::Melee => (
+                Enemy {
+                    attack: 10.0,
+                    roll: DamageRoll {
+                        crit_chance: 0.0,
+                        crit_multiplier: 1.0,
+                        variance: MELEE_ENEMY_DAMAGE_VARIANCE,
+                    },
+                    attack_range: TILE_SIZE * 0.3,
+                    speed: 3.0 * TILE_SIZE,
+                    timer: Timer::from_seconds(0.5, true),
+                    kind,
+                    ..Default::default()
+                },
+                COLOR_ENEMY,
+                Health::new(100.0).with_regen(MELEE_ENEMY_REGEN),
+            ),
+            EnemyKind::Ranged => (
+                Enemy {
+                    attack: 8.0,
+                    roll: DamageRoll {
+                        crit_chance: RANGED_ENEMY_CRIT_CHANCE,
+                        crit_multiplier: RANGED_ENEMY_CRIT_MULTIPLIER,
+                        variance: 0.0,
+                    },
+                    attack_range: TILE_SIZE * 4.0,
+                    speed: 2.0 * TILE_SIZE,
+                    timer: Timer::from_seconds(1.5, true),
+                    kind,
+                    ..Default::default()
+                },
+                COLOR_RANGED_ENEMY,
+                Health::new(60.0).with_shield(RANGED_ENEMY_SHIELD, RANGED_ENEMY_SHIELD_REGEN),
+            ),
+        };
+        health.make_invulnerable(ENEMY_SPAWN_INVULNERABILITY);
         let enemy = commands
             .spawn_bundle(SpriteBundle {
                 sprite: Sprite {
-                    color: COLOR_ENEMY,
+                    color,
                     custom_size: Some(Vec2::splat(TILE_SIZE * 0.7)),
                     ..Default::default()
                 },
-                texture: asset_server.load("sprites/enemy.png"),
+                texture: assets.enemy.clone(),
                 transform: Transform {
                     translation,
                     ..Default::default()
                 },
                 ..Default::default()
             })
-            .insert(Enemy {
-                attack: 10.0,
-                speed: 3.0 * TILE_SIZE,
-                timer: Timer::from_seconds(0.5, true),
-            })
+            .insert(enemy)
             .insert(Name::new("Enemy"))
-            .insert(Health {
-                current: 100.0,
-                max: 100.0,
-            })
+            .insert(Faction::Enemy)
+            .insert(health)
             .id();
         let hp_bar = create_hp_bar(
-            &mut commands,
+            commands,
             Vec2::new(0.0, TILE_SIZE * 0.5),
             Vec2::new(TILE_SIZE * 0.85, TILE_SIZE * 0.1),
             enemy,
         );
         commands.entity(enemy).add_child(hp_bar);
+        enemy
     }
 }
@@ -1,47 +1,387 @@
 use bevy::{prelude::*, sprite::Anchor};
+use bevy_inspector_egui::Inspectable;
 
-use crate::constants::*;
+use crate::{
+    building::{Repairing, UnderConstruction},
+    combat_text::DamageEvent,
+    constants::*,
+    rng::GameRng,
+    user_interface::{CursorWorldPos, MainCamera, SelectedBuilding},
+};
+
+/// What a bar's fill level tracks.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum BarKind {
+    Health,
+    Construction,
+}
 
 #[derive(Component)]
 pub struct HPBar {
     parent: Entity,
     offset: Vec3,
     size: Vec2,
+    kind: BarKind,
+}
+
+/// Second bar drawn above a health bar, tracking the parent's shield.
+#[derive(Component)]
+pub struct ShieldBar {
+    parent: Entity,
+    size: Vec2,
+}
+
+/// Dark background behind a bar, shown and hidden together with it.
+#[derive(Component)]
+struct BarFrame;
+
+/// Optional randomness applied to an attacker's base damage.
+#[derive(Clone, Copy, Default, Debug, Reflect, Inspectable)]
+pub struct DamageRoll {
+    /// Probability in `0..=1` of a critical hit.
+    pub crit_chance: f32,
+    pub crit_multiplier: f32,
+    /// Damage varies by up to this fraction either way.
+    pub variance: f32,
+}
+
+impl DamageRoll {
+    pub fn roll(&self, base: f32, rng: &mut GameRng) -> Hit {
+        let damage = base * rng.spread(self.variance);
+        if rng.chance(self.crit_chance) {
+            Hit {
+                damage: damage * self.crit_multiplier,
+                crit: true,
+            }
+        } else {
+            Hit {
+                damage,
+                crit: false,
+            }
+        }
+    }
+}
+
+/// A single rolled attack.
+#[derive(Clone, Copy, Debug)]
+pub struct Hit {
+    pub damage: f32,
+    pub crit: bool,
+}
+
+/// How a hit was split between shield and health.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct DamageDealt {
+    pub health: f32,
+    pub shield: f32,
 }
 
 #[derive(Component)]
 pub struct Health {
     pub current: f32,
     pub max: f32,
+    /// Health restored per second while alive.
+    pub regen: f32,
+    /// Absorbs damage before health does.
+    pub shield: f32,
+    pub max_shield: f32,
+    /// Shield restored per second once `SHIELD_REGEN_DELAY` has passed since the last hit.
+    pub shield_regen: f32,
+    since_damage: f32,
+    /// Seconds of invulnerability left.
+    invulnerable: f32,
+}
+
+impl Health {
+    pub fn new(max: f32) -> Self {
+        Health {
+            current: max,
+            max,
+            regen: 0.0,
+            shield: 0.0,
+            max_shield: 0.0,
+            shield_regen: 0.0,
+            since_damage: 0.0,
+            invulnerable: 0.0,
+        }
+    }
+
+    pub fn with_current(mut self, current: f32) -> Self {
+        self.current = current;
+        self
+    }
+
+    pub fn with_regen(mut self, regen: f32) -> Self {
+        self.regen = regen;
+        self
+    }
+
+    pub fn with_shield(mut self, max_shield: f32, shield_regen: f32) -> Self {
+        self.shield = max_shield;
+        self.max_shield = max_shield;
+        self.shield_regen = shield_regen;
+        self
+    }
+
+    pub fn is_invulnerable(&self) -> bool {
+        self.invulnerable > 0.0
+    }
+
+    /// Ignores all damage for the next `seconds`, extending any running window.
+    pub fn make_invulnerable(&mut self, seconds: f32) {
+        self.invulnerable = self.invulnerable.max(seconds);
+    }
+
+    /// Applies damage to the shield first and then to health.
+    pub fn damage(&mut self, amount: f32) -> DamageDealt {
+        if self.is_invulnerable() || amount <= 0.0 {
+            return DamageDealt::default();
+        }
+        self.since_damage = 0.0;
+        let absorbed = amount.min(self.shield);
+        self.shield -= absorbed;
+        self.current -= amount - absorbed;
+        DamageDealt {
+            health: amount - absorbed,
+            shield: absorbed,
+        }
+    }
+
+    fn tick(&mut self, delta: f32) {
+        self.invulnerable = (self.invulnerable - delta).max(0.0);
+        self.since_damage += delta;
+        if self.current <= 0.0 {
+            return;
+        }
+        self.current = (self.current + self.regen * delta).min(self.max);
+        if self.since_damage >= SHIELD_REGEN_DELAY {
+            self.shield = (self.shield + self.shield_regen * delta).min(self.max_shield);
+        }
+    }
 }
+
+/// Health simulation, needed with or without rendering.
+pub struct HealthPlugin;
+
+impl Plugin for HealthPlugin {
+    fn build(&self, app: &mut App) {
+        // Also runs on clients so invulnerability windows expire there, snapshots
+        // overwrite the health values anyway
+        app.add_event::<DamageEvent>().add_system(regenerate_health);
+    }
+}
+
+/// Draws health and shield bars.
 pub struct HPBarsPlugin;
 
 impl Plugin for HPBarsPlugin {
     fn build(&self, app: &mut App) {
-        app.add_system(update_hp_bars);
+        app.add_system(update_hp_bars)
+            .add_system(update_shield_bars.after(update_hp_bars))
+            .add_system(update_bar_frames.after(update_shield_bars));
+    }
+}
+
+fn regenerate_health(mut q_health: Query<&mut Health>, time: Res<Time>) {
+    for mut health in q_health.iter_mut() {
+        health.tick(time.delta_seconds());
+    }
+}
+
+/// World-space rectangle currently covered by the main camera.
+struct CameraView {
+    min: Vec2,
+    max: Vec2,
+}
+
+impl CameraView {
+    fn new(transform: &Transform, projection: &OrthographicProjection) -> Self {
+        let center = transform.translation.truncate();
+        // Leave a tile of margin so bars don't pop in at the screen edge
+        let margin = Vec2::splat(TILE_SIZE);
+        CameraView {
+            min: center + Vec2::new(projection.left, projection.bottom) * projection.scale - margin,
+            max: center + Vec2::new(projection.right, projection.top) * projection.scale + margin,
+        }
     }
+
+    fn contains(&self, point: Vec2) -> bool {
+        point.cmpge(self.min).all() && point.cmple(self.max).all()
+    }
+}
+
+/// Blends from red at zero health to green at full health.
+fn health_color(fill: f32) -> Color {
+    let low = COLOR_HP_BAR_LOW;
+    let high = COLOR_HP_BAR;
+    Color::rgb(
+        low.r() + (high.r() - low.r()) * fill,
+        low.g() + (high.g() - low.g()) * fill,
+        low.b() + (high.b() - low.b()) * fill,
+    )
 }
 
 pub fn update_hp_bars(
-    mut q_bars: Query<(&HPBar, &mut Sprite, &mut Transform), With<HPBar>>,
-    q_units: Query<(&Transform, &Health), Without<HPBar>>,
+    mut q_bars: Query<(&HPBar, &mut Sprite, &mut Transform, &mut Visibility)>,
+    q_units: Query<
+        (
+            &Transform,
+            &Health,
+            Option<&Repairing>,
+            Option<&UnderConstruction>,
+        ),
+        Without<HPBar>,
+    >,
+    q_camera: Query<(&Transform, &OrthographicProjection), (With<MainCamera>, Without<HPBar>)>,
+    cursor: Res<CursorWorldPos>,
+    selected: Res<SelectedBuilding>,
 ) {
-    for (bar, mut sprite, mut local) in q_bars.iter_mut() {
-        // local.rotation = local.rotation - global.rotation;
-        // let a = global.rotation;
+    let view = match q_camera.get_single() {
+        Ok((transform, projection)) => CameraView::new(transform, projection),
+        Err(_) => return,
+    };
+    for (bar, mut sprite, mut local, mut visibility) in q_bars.iter_mut() {
+        if let Ok((transform, hp, repairing, construction)) = q_units.get(bar.parent) {
+            let position = transform.translation.truncate();
+            if !view.contains(position) {
+                continue;
+            }
+            let hovered = cursor
+                .0
+                .map_or(false, |cursor| cursor.distance(position) < TILE_SIZE * 0.5);
+            let fill = match bar.kind {
+                BarKind::Health => (hp.current / hp.max).clamp(0.0, 1.0),
+                BarKind::Construction => construction.map_or(1.0, |c| c.timer.percent()),
+            };
+            visibility.is_visible = match bar.kind {
+                BarKind::Health => {
+                    fill < 1.0
+                        || hp.shield < hp.max_shield
+                        || hovered
+                        || selected.0 == Some(bar.parent)
+                }
+                BarKind::Construction => true,
+            };
+            if !visibility.is_visible {
+                continue;
+            }
 
-        if let Ok((transform, hp)) = q_units.get(bar.parent) {
             let inverse = transform.rotation.inverse();
             local.translation = inverse.mul_vec3(bar.offset);
             local.rotation = inverse;
             if let Some(size) = sprite.custom_size.as_mut() {
-                size.x = bar.size.x * (hp.current / hp.max);
+                size.x = bar.size.x * fill;
+            }
+            if bar.kind == BarKind::Health {
+                sprite.color = if hp.is_invulnerable() {
+                    COLOR_HP_BAR_INVULNERABLE
+                } else if repairing.is_some() {
+                    COLOR_HP_BAR_REPAIRING
+                } else {
+                    health_color(fill)
+                };
             }
         }
     }
 }
 
+/// Shield bars sit on their health bar and are only shown along with it.
+fn update_shield_bars(
+    mut q_bars: Query<(&ShieldBar, &Parent, &mut Sprite, &mut Visibility)>,
+    q_hp_bars: Query<&Visibility, (With<HPBar>, Without<ShieldBar>)>,
+    q_units: Query<(&Transform, &Health)>,
+    q_camera: Query<(&Transform, &OrthographicProjection), With<MainCamera>>,
+) {
+    let view = match q_camera.get_single() {
+        Ok((transform, projection)) => CameraView::new(transform, projection),
+        Err(_) => return,
+    };
+    for (bar, hp_bar, mut sprite, mut visibility) in q_bars.iter_mut() {
+        if let Ok((transform, hp)) = q_units.get(bar.parent) {
+            if !view.contains(transform.translation.truncate()) {
+                continue;
+            }
+            let hp_bar_visible = q_hp_bars
+                .get(hp_bar.get())
+                .map_or(false, |visibility| visibility.is_visible);
+            visibility.is_visible = hp_bar_visible && hp.max_shield > 0.0;
+            if let Some(size) = sprite.custom_size.as_mut() {
+                size.x = bar.size.x * (hp.shield / hp.max_shield.max(f32::EPSILON));
+            }
+        }
+    }
+}
+
+fn update_bar_frames(
+    mut q_frames: Query<(&Parent, &mut Visibility), With<BarFrame>>,
+    q_bars: Query<&Visibility, Without<BarFrame>>,
+) {
+    for (bar, mut visibility) in q_frames.iter_mut() {
+        if let Ok(bar_visibility) = q_bars.get(bar.get()) {
+            if visibility.is_visible != bar_visibility.is_visible {
+                visibility.is_visible = bar_visibility.is_visible;
+            }
+        }
+    }
+}
+
+/// Creates a health bar with a shield bar stacked on top of it.
+/// The shield bar stays hidden for units without a shield.
 pub fn create_hp_bar(commands: &mut Commands, offset: Vec2, size: Vec2, parent: Entity) -> Entity {
+    let hp_bar = create_bar(commands, offset, size, parent, BarKind::Health);
+    let shield_frame = commands
+        .spawn_bundle(SpriteBundle {
+            sprite: Sprite {
+                color: Color::rgb(0.1, 0.1, 0.1),
+                custom_size: Some(Vec2::new(
+                    size.x + TILE_SIZE * 0.05,
+                    size.y + TILE_SIZE * 0.05,
+                )),
+                anchor: Anchor::CenterLeft,
+                ..Default::default()
+            },
+            transform: Transform {
+                translation: Vec3::new(-TILE_SIZE * 0.025, 0.0, -0.1),
+                ..Default::default()
+            },
+            visibility: Visibility { is_visible: false },
+            ..Default::default()
+        })
+        .insert(BarFrame)
+        .id();
+    let shield_bar = commands
+        .spawn_bundle(SpriteBundle {
+            sprite: Sprite {
+                color: COLOR_SHIELD_BAR,
+                custom_size: Some(size),
+                anchor: Anchor::CenterLeft,
+                ..Default::default()
+            },
+            transform: Transform {
+                translation: Vec3::new(0.0, size.y + TILE_SIZE * 0.05, 0.0),
+                ..Default::default()
+            },
+            visibility: Visibility { is_visible: false },
+            ..Default::default()
+        })
+        .insert(ShieldBar { parent, size })
+        .add_child(shield_frame)
+        .id();
+    commands.entity(hp_bar).add_child(shield_bar);
+    hp_bar
+}
+
+pub fn create_bar(
+    commands: &mut Commands,
+    offset: Vec2,
+    size: Vec2,
+    parent: Entity,
+    kind: BarKind,
+) -> Entity {
+    let color = match kind {
+        BarKind::Health => COLOR_HP_BAR,
+        BarKind::Construction => COLOR_CONSTRUCTION_BAR,
+    };
     let hp_frame = commands
         .spawn_bundle(SpriteBundle {
             sprite: Sprite {
@@ -57,13 +397,17 @@ pub fn create_hp_bar(commands: &mut Commands, offset: Vec2, size: Vec2, parent:
                 translation: Vec3::new(-TILE_SIZE * 0.025, 0.0, -0.1),
                 ..Default::default()
             },
+            visibility: Visibility {
+                is_visible: kind != BarKind::Health,
+            },
             ..Default::default()
         })
+        .insert(BarFrame)
         .id();
     commands
         .spawn_bundle(SpriteBundle {
             sprite: Sprite {
-                color: Color::rgb(0.1, 0.9, 0.1),
+                color,
                 custom_size: Some(size),
                 anchor: Anchor::CenterLeft,
                 ..Default::default()
@@ -72,12 +416,17 @@ pub fn create_hp_bar(commands: &mut Commands, offset: Vec2, size: Vec2, parent:
                 translation: Vec3::new(-size.x / 2.0, offset.y, 11.0),
                 ..Default::default()
             },
+            // Health bars stay hidden until the unit takes damage
+            visibility: Visibility {
+                is_visible: kind != BarKind::Health,
+            },
             ..Default::default()
         })
         .insert(HPBar {
             parent,
             offset: Vec3::new(-size.x / 2.0, offset.y, 11.0),
             size,
+            kind,
         })
         .add_child(hp_frame)
         .id()
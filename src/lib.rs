@@ -0,0 +1,59 @@
+use bevy::prelude::*;
+
+use crate::{building::BuildingPlugin, economy::EconomyPlugin, networking::NetworkingPlugin};
+use assets::GameAssets;
+use enemy::EnemyPlugin;
+use hp_bar::HealthPlugin;
+use lifecycle::LifecyclePlugin;
+use map::{MapPlugin, MapSettings};
+use power::PowerPlugin;
+use projectile::ProjectilePlugin;
+use rng::GameRng;
+use tower::TowerPlugin;
+extern crate noise;
+
+pub mod assets;
+pub mod building;
+pub mod cli;
+pub mod combat_text;
+pub mod constants;
+pub mod debug;
+pub mod economy;
+pub mod enemy;
+pub mod hp_bar;
+pub mod lifecycle;
+pub mod map;
+pub mod networking;
+pub mod pathfinding;
+pub mod power;
+pub mod projectile;
+pub mod rng;
+#[cfg(test)]
+mod testing;
+pub mod tower;
+pub mod user_interface;
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub enum AppState {
+    Main,
+    Building,
+}
+
+/// Everything that simulates the game, without windows, rendering or input.
+pub fn add_simulation(app: &mut App, networking: NetworkingPlugin, map: MapSettings, seed: u64) {
+    app.add_state(AppState::Main)
+        // Registers the despawn stage other plugins schedule systems in
+        .add_plugin(LifecyclePlugin)
+        .add_plugin(networking)
+        .init_resource::<GameAssets>()
+        .insert_resource(map)
+        .add_plugin(MapPlugin)
+        .add_plugin(TowerPlugin)
+        .add_plugin(EnemyPlugin)
+        .add_plugin(ProjectilePlugin)
+        .add_plugin(HealthPlugin)
+        .add_plugin(EconomyPlugin)
+        .insert_resource(GameRng::new(seed))
+        .add_plugin(BuildingPlugin)
+        .add_plugin(PowerPlugin);
+}
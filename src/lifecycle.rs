@@ -0,0 +1,69 @@
+use bevy::prelude::*;
+
+/// Runs right after `CoreStage::Update`, so every system sees an entity either
+/// fully alive or already gone.
+#[derive(StageLabel)]
+pub struct DespawnStage;
+
+/// Marks an entity for removal. Inserting it several times in one frame is
+/// harmless, the entity is despawned exactly once in `DespawnStage`.
+#[derive(Component)]
+pub struct Despawning;
+
+pub struct LifecyclePlugin;
+
+impl Plugin for LifecyclePlugin {
+    fn build(&self, app: &mut App) {
+        app.add_stage_after(
+            CoreStage::Update,
+            DespawnStage,
+            SystemStage::single_threaded(),
+        )
+        .add_system_to_stage(DespawnStage, despawn_marked);
+    }
+}
+
+fn despawn_marked(
+    mut commands: Commands,
+    q_marked: Query<Entity, With<Despawning>>,
+    q_parents: Query<&Parent>,
+) {
+    for entity in q_marked.iter() {
+        // A marked ancestor takes its children with it, despawning them again would fail
+        let mut ancestor = q_parents.get(entity).ok().map(|parent| parent.get());
+        let mut covered = false;
+        while let Some(current) = ancestor {
+            if q_marked.contains(current) {
+                covered = true;
+                break;
+            }
+            ancestor = q_parents.get(current).ok().map(|parent| parent.get());
+        }
+        if !covered {
+            commands.entity(entity).despawn_recursive();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::headless_app;
+
+    #[test]
+    fn marked_entities_are_despawned_once() {
+        let mut app = headless_app();
+        let child = app.world.spawn().id();
+        let parent = app.world.spawn().push_children(&[child]).id();
+        // Marked twice and together with its child, as when a hit and a kill coincide
+        app.world
+            .entity_mut(parent)
+            .insert(Despawning)
+            .insert(Despawning);
+        app.world.entity_mut(child).insert(Despawning);
+        app.update();
+
+        assert!(app.world.get_entity(parent).is_none());
+        assert!(app.world.get_entity(child).is_none());
+    }
+}
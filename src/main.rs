@@ -1,46 +1,38 @@
-use crate::{building::BuildingPlugin, networking::NetworkingPlugin};
+use std::time::Duration;
+
+use base_defense::{
+    add_simulation, cli::Options, combat_text::CombatTextPlugin, constants::*, debug::DebugPlugin,
+    hp_bar::HPBarsPlugin, map::MapSettings, networking::NetworkingPlugin,
+    user_interface::UserInterfacePlugin,
+};
 use bevy::{
-    log::{Level, LogSettings},
+    app::ScheduleRunnerSettings,
+    hierarchy::HierarchyPlugin,
+    log::{Level, LogPlugin, LogSettings},
     prelude::*,
+    transform::TransformPlugin,
 };
-use debug::DebugPlugin;
-use enemy::EnemyPlugin;
-use hp_bar::HPBarsPlugin;
-use map::MapPlugin;
-use projectile::ProjectilePlugin;
-use tower::TowerPlugin;
-use user_interface::UserInterfacePlugin;
-extern crate noise;
-use constants::*;
-
-mod building;
-mod constants;
-mod debug;
-mod enemy;
-mod hp_bar;
-mod map;
-mod networking;
-mod projectile;
-mod tower;
-mod user_interface;
-
-#[derive(Debug, Clone, Eq, PartialEq, Hash)]
-enum AppState {
-    Main,
-    Building,
-}
-
-pub struct PlayerResources {
-    pub gold: f32,
-}
 
 fn main() {
-    println!("Usage: server [SERVER_PORT] or client [SERVER_PORT] [USER_NAME]");
-    let args: Vec<String> = std::env::args().collect();
+    let options = Options::parse();
 
-    let height = 900.0;
-    App::new()
-        .insert_resource(WindowDescriptor {
+    let mut app = App::new();
+    app.insert_resource(LogSettings {
+        level: Level::TRACE,
+        filter: "info,wgpu_core=warn,wgpu_hal=warn,base_defense::projectile=debug".to_string(),
+    });
+    if options.headless {
+        // No window to pace frames, so tick at a fixed rate instead of spinning
+        app.insert_resource(ScheduleRunnerSettings::run_loop(Duration::from_secs_f64(
+            1.0 / SERVER_TICK_RATE,
+        )))
+        .add_plugins(MinimalPlugins)
+        .add_plugin(LogPlugin)
+        .add_plugin(TransformPlugin)
+        .add_plugin(HierarchyPlugin);
+    } else {
+        let height = 900.0;
+        app.insert_resource(WindowDescriptor {
             width: height * RESOLUTION,
             height: height,
             title: "Base defense".to_string(),
@@ -48,24 +40,23 @@ fn main() {
             resizable: false,
             ..Default::default()
         })
-        .insert_resource(LogSettings {
-            level: Level::TRACE,
-            filter: "info,wgpu_core=warn,wgpu_hal=warn,base_defense::projectile=debug".to_string(),
-        })
-        .insert_resource(PlayerResources {
-            gold: STARTING_GOLD,
-        })
-        .add_plugins(DefaultPlugins)
-        .add_state(AppState::Main)
-        .add_plugin(NetworkingPlugin::new(&args))
-        .add_plugin(MapPlugin)
-        .add_plugin(TowerPlugin)
-        .add_plugin(UserInterfacePlugin)
-        .add_plugin(DebugPlugin)
-        .add_plugin(EnemyPlugin)
-        .add_plugin(ProjectilePlugin)
-        .add_plugin(HPBarsPlugin)
-        .add_plugin(BuildingPlugin)
-        .add_system(bevy::window::close_on_esc)
-        .run();
+        .add_plugins(DefaultPlugins);
+    }
+
+    add_simulation(
+        &mut app,
+        NetworkingPlugin::new(options.role, options.server_addr, options.username),
+        MapSettings::new(options.map_size, options.seed),
+        options.seed,
+    );
+
+    if !options.headless {
+        app.add_plugin(UserInterfacePlugin)
+            .add_plugin(DebugPlugin)
+            .add_plugin(HPBarsPlugin)
+            .add_plugin(CombatTextPlugin)
+            .add_system(bevy::window::close_on_esc);
+    }
+
+    app.run();
 }
@@ -1,33 +1,192 @@
-use crate::constants::*;
+use crate::{assets::GameAssets, constants::*, economy::ResourceKind};
 use bevy::prelude::*;
-use noise::{utils::PlaneMapBuilder, OpenSimplex};
+use noise::{utils::PlaneMapBuilder, OpenSimplex, Seedable};
 extern crate noise;
 use bevy::prelude::Color;
 use noise::utils::*;
+use serde::{Deserialize, Serialize};
 #[derive(Component)]
 pub struct Map;
 
 pub struct MapPlugin;
 
+/// Size and noise seed of the generated map. Clients use the server's.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct MapSettings {
+    pub size: i32,
+    pub seed: u32,
+}
+
+impl MapSettings {
+    pub fn new(size: i32, game_seed: u64) -> Self {
+        MapSettings {
+            size,
+            seed: (game_seed ^ (game_seed >> 32)) as u32,
+        }
+    }
+}
+
+impl Default for MapSettings {
+    fn default() -> Self {
+        MapSettings::new(MAP_SIZE, DEFAULT_SEED)
+    }
+}
+
 impl Plugin for MapPlugin {
     fn build(&self, app: &mut App) {
-        app.add_startup_system(create_simple_map);
+        let settings = *app.world.get_resource_or_insert_with(MapSettings::default);
+        app.insert_resource(TileGrid::generate(&settings))
+            .add_system(draw_map);
     }
 }
 
-fn create_simple_map(mut commands: Commands, asset_server: Res<AssetServer>) {
-    let mut tiles = Vec::new();
-    tiles.reserve_exact(10000);
+/// Orthogonal neighbours of a tile.
+pub const NEIGHBOURS: [IVec2; 4] = [
+    IVec2::new(1, 0),
+    IVec2::new(-1, 0),
+    IVec2::new(0, 1),
+    IVec2::new(0, -1),
+];
+
+pub fn world_to_tile(pos: Vec2) -> IVec2 {
+    ((pos + TILE_SIZE / 2.0) / TILE_SIZE).floor().as_ivec2()
+}
+
+pub fn tile_to_world(tile: IVec2) -> Vec2 {
+    tile.as_vec2() * TILE_SIZE
+}
+
+/// Which building, if any, occupies each map tile and which resource can be extracted there.
+/// Occupied tiles can't be walked through.
+pub struct TileGrid {
+    size: i32,
+    occupants: Vec<Option<Entity>>,
+    deposits: Vec<Option<ResourceKind>>,
+    /// Bumped every time passability changes, so paths know when to be recomputed.
+    pub version: u32,
+}
+
+impl TileGrid {
+    pub fn new(size: i32) -> TileGrid {
+        TileGrid {
+            size,
+            occupants: vec![None; (size * size) as usize],
+            deposits: vec![None; (size * size) as usize],
+            version: 0,
+        }
+    }
+
+    /// An empty grid with the deposits `settings` produce.
+    pub fn generate(settings: &MapSettings) -> TileGrid {
+        let mut grid = TileGrid::new(settings.size);
+        generate_deposits(&mut grid, settings);
+        grid
+    }
+
+    fn index(&self, tile: IVec2) -> Option<usize> {
+        let x = tile.x + self.size / 2;
+        let y = tile.y + self.size / 2;
+        if x < 0 || y < 0 || x >= self.size || y >= self.size {
+            return None;
+        }
+        Some((y * self.size + x) as usize)
+    }
+
+    pub fn contains(&self, tile: IVec2) -> bool {
+        self.index(tile).is_some()
+    }
+
+    pub fn occupant(&self, tile: IVec2) -> Option<Entity> {
+        self.index(tile).and_then(|i| self.occupants[i])
+    }
 
-    let simplex = OpenSimplex::default();
+    pub fn deposit(&self, tile: IVec2) -> Option<ResourceKind> {
+        self.index(tile).and_then(|i| self.deposits[i])
+    }
+
+    pub fn set_deposit(&mut self, tile: IVec2, deposit: Option<ResourceKind>) {
+        if let Some(i) = self.index(tile) {
+            self.deposits[i] = deposit;
+        }
+    }
 
-    let noise_map = PlaneMapBuilder::new(&simplex)
-        .set_size(MAP_SIZE as usize, MAP_SIZE as usize)
-        .set_x_bounds(-BOUND_SIZE, BOUND_SIZE)
-        .set_y_bounds(-BOUND_SIZE, BOUND_SIZE)
-        .build();
-    for y in -MAP_SIZE / 2..MAP_SIZE / 2 {
-        for x in -MAP_SIZE / 2..MAP_SIZE / 2 {
+    pub fn is_passable(&self, tile: IVec2) -> bool {
+        self.contains(tile) && self.occupant(tile).is_none()
+    }
+
+    pub fn occupy(&mut self, tile: IVec2, entity: Entity) {
+        if let Some(i) = self.index(tile) {
+            self.occupants[i] = Some(entity);
+            self.version += 1;
+        }
+    }
+
+    pub fn free(&mut self, tile: IVec2) {
+        if let Some(i) = self.index(tile) {
+            self.occupants[i] = None;
+            self.version += 1;
+        }
+    }
+}
+
+/// Resources found on the map, each generated from its own noise layer, offset from the map
+/// seed. A tile gets the first deposit in this list whose noise value exceeds the threshold.
+const DEPOSITS: [(ResourceKind, u32, f32); 4] = [
+    (ResourceKind::Gold, 0, 0.8),
+    (ResourceKind::Ore, 1, 0.8),
+    (ResourceKind::Stone, 2, 0.75),
+    (ResourceKind::Wood, 3, 0.7),
+];
+
+fn generate_deposits(grid: &mut TileGrid, settings: &MapSettings) {
+    let size = settings.size;
+    let noise_maps: Vec<(ResourceKind, f32, NoiseMap)> = DEPOSITS
+        .iter()
+        .map(|&(kind, layer, threshold)| {
+            let simplex = OpenSimplex::default().set_seed(settings.seed.wrapping_add(layer));
+            let noise_map = PlaneMapBuilder::new(&simplex)
+                .set_size(size as usize, size as usize)
+                .set_x_bounds(-BOUND_SIZE, BOUND_SIZE)
+                .set_y_bounds(-BOUND_SIZE, BOUND_SIZE)
+                .build();
+            (kind, threshold, noise_map)
+        })
+        .collect();
+    for y in -size / 2..size / 2 {
+        for x in -size / 2..size / 2 {
+            let deposit = noise_maps.iter().find(|(_, threshold, noise_map)| {
+                let gray = (noise_map.get_value((x + size / 2) as usize, (y + size / 2) as usize)
+                    + 0.5)
+                    .clamp(0.0, 1.0) as f32;
+                gray > *threshold
+            });
+            if let Some(&(kind, _, _)) = deposit {
+                grid.set_deposit(IVec2::new(y, x), Some(kind));
+            }
+        }
+    }
+}
+
+/// Draws the map whenever its settings change, e.g. when a client learns the server's.
+fn draw_map(
+    mut commands: Commands,
+    assets: Res<GameAssets>,
+    grid: Res<TileGrid>,
+    settings: Res<MapSettings>,
+    q_map: Query<Entity, With<Map>>,
+) {
+    if !settings.is_changed() {
+        return;
+    }
+    for map in q_map.iter() {
+        commands.entity(map).despawn_recursive();
+    }
+
+    let size = settings.size;
+    let mut tiles = Vec::new();
+    tiles.reserve_exact((size * size) as usize);
+    for y in -size / 2..size / 2 {
+        for x in -size / 2..size / 2 {
             let tile = commands
                 .spawn_bundle(SpriteBundle {
                     sprite: Sprite {
@@ -44,19 +203,15 @@ fn create_simple_map(mut commands: Commands, asset_server: Res<AssetServer>) {
                 .id();
             tiles.push(tile);
 
-            let gray = (noise_map
-                .get_value((x + MAP_SIZE / 2) as usize, (y + MAP_SIZE / 2) as usize)
-                + 0.5)
-                .clamp(0.0, 1.0) as f32;
-            if gray > 0.8 {
-                let gold = commands
+            if let Some(kind) = grid.deposit(IVec2::new(y, x)) {
+                let deposit = commands
                     .spawn_bundle(SpriteBundle {
                         sprite: Sprite {
-                            color: Color::rgb(1.0, 0.8, 0.0),
+                            color: kind.color(),
                             custom_size: Some(Vec2::splat(TILE_SIZE * 0.6)),
                             ..Default::default()
                         },
-                        texture: asset_server.load("sprites/projectile.png"),
+                        texture: assets.projectile.clone(),
                         transform: Transform {
                             translation: Vec3::new(y as f32 * TILE_SIZE, x as f32 * TILE_SIZE, 1.0),
                             ..Default::default()
@@ -64,7 +219,7 @@ fn create_simple_map(mut commands: Commands, asset_server: Res<AssetServer>) {
                         ..Default::default()
                     })
                     .id();
-                tiles.push(gold)
+                tiles.push(deposit)
             };
         }
     }
@@ -1,67 +1,363 @@
 use std::{
-    net::{SocketAddr, UdpSocket},
+    fmt, fs,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket},
+    process,
     time::SystemTime,
 };
 
-use bevy::prelude::*;
+use bevy::{ecs::schedule::ShouldRun, prelude::*, utils::HashMap};
 use bevy_renet::{
     renet::{
-        ClientAuthentication, RenetClient, RenetConnectionConfig, RenetServer,
-        ServerAuthentication, ServerConfig,
+        BlockChannelConfig, ChannelConfig, ClientAuthentication, ReliableChannelConfig,
+        RenetClient, RenetConnectionConfig, RenetServer, ServerAuthentication, ServerConfig,
+        UnreliableChannelConfig,
     },
     RenetClientPlugin, RenetServerPlugin,
 };
 use renet_visualizer::{RenetClientVisualizer, RenetVisualizerStyle};
+use serde::Serialize;
+
+use crate::{constants::*, lifecycle::DespawnStage};
+use interpolation::{InterpolationSettings, SnapshotClock};
+use protocol::{ConnectInfo, NetworkId};
+use replication::{BandwidthLog, ReplicationState};
+use snapshots::SnapshotAssembler;
 
 const PROTOCOL_ID: u64 = 0;
 
-// Helper struct to pass an username in the user data
-struct Username(String);
+/// Renet channels, each with its own delivery guarantees and bandwidth budget so a burst
+/// on one can't hold up the others.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Channel {
+    /// Commands, chat and spawn/despawn notices. Must arrive, in order.
+    Reliable,
+    /// Snapshots. A lost one is superseded by the next anyway.
+    Unreliable,
+    /// Large one-off transfers such as the state sync for a joining client.
+    Block,
+}
+
+impl Channel {
+    pub const ALL: [Channel; 3] = [Channel::Reliable, Channel::Unreliable, Channel::Block];
+
+    pub fn id(self) -> u8 {
+        match self {
+            Channel::Reliable => 0,
+            Channel::Unreliable => 1,
+            Channel::Block => 2,
+        }
+    }
+
+    pub fn max_message_size(self) -> u64 {
+        match self {
+            Channel::Reliable => RELIABLE_MAX_MESSAGE_SIZE,
+            Channel::Unreliable => UNRELIABLE_MAX_MESSAGE_SIZE,
+            Channel::Block => BLOCK_MAX_MESSAGE_SIZE,
+        }
+    }
+
+    /// Encodes `message` for this channel. Anything renet would reject is dropped here
+    /// instead, since an oversized message makes renet close the whole connection.
+    pub fn encode<T: Serialize + fmt::Debug>(self, message: &T) -> Option<Vec<u8>> {
+        match protocol::encode(message) {
+            Ok(bytes) if bytes.len() as u64 > self.max_message_size() => {
+                error!(
+                    "Dropping {:?}, {} bytes is too large for the {:?} channel",
+                    message,
+                    bytes.len(),
+                    self
+                );
+                None
+            }
+            Ok(bytes) => Some(bytes),
+            Err(err) => {
+                error!("Failed to encode {:?}: {}", message, err);
+                None
+            }
+        }
+    }
+
+    fn config(self) -> ChannelConfig {
+        match self {
+            Channel::Reliable => ReliableChannelConfig {
+                channel_id: self.id(),
+                packet_budget: RELIABLE_PACKET_BUDGET,
+                max_message_size: self.max_message_size(),
+                ..Default::default()
+            }
+            .into(),
+            Channel::Unreliable => UnreliableChannelConfig {
+                channel_id: self.id(),
+                packet_budget: UNRELIABLE_PACKET_BUDGET,
+                max_message_size: self.max_message_size(),
+                ..Default::default()
+            }
+            .into(),
+            Channel::Block => BlockChannelConfig {
+                channel_id: self.id(),
+                packet_budget: BLOCK_PACKET_BUDGET,
+                max_message_size: self.max_message_size(),
+                ..Default::default()
+            }
+            .into(),
+        }
+    }
+}
+
+/// Both ends must agree on the channel layout.
+fn connection_config() -> RenetConnectionConfig {
+    let channels: Vec<ChannelConfig> = Channel::ALL
+        .iter()
+        .map(|channel| channel.config())
+        .collect();
+    RenetConnectionConfig {
+        send_channels_config: channels.clone(),
+        receive_channels_config: channels,
+        ..Default::default()
+    }
+}
+
+/// Which part this process plays in a game.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum NetworkRole {
+    Singleplayer,
+    /// Owns the simulation and tells clients what happened.
+    Server,
+    /// Only renders what the server replicates and sends commands back.
+    Client,
+}
+
+impl NetworkRole {
+    pub fn is_authority(self) -> bool {
+        self != NetworkRole::Client
+    }
+}
+
+/// Run criteria for systems that change the game state, so they only run where the
+/// simulation lives.
+pub fn run_if_authority(role: Res<NetworkRole>) -> ShouldRun {
+    if role.is_authority() {
+        ShouldRun::Yes
+    } else {
+        ShouldRun::No
+    }
+}
+
+/// Maps replicated ids to local entities. The server hands out the ids.
+#[derive(Default)]
+pub struct NetworkEntities {
+    next: u64,
+    entities: HashMap<NetworkId, Entity>,
+    /// Ids of entities that stopped being replicated without despawning.
+    released: Vec<NetworkId>,
+}
+
+impl NetworkEntities {
+    pub fn allocate(&mut self, entity: Entity) -> NetworkId {
+        let id = NetworkId(self.next);
+        self.next += 1;
+        self.entities.insert(id, entity);
+        id
+    }
+
+    pub fn insert(&mut self, id: NetworkId, entity: Entity) {
+        self.entities.insert(id, entity);
+    }
+
+    pub fn get(&self, id: NetworkId) -> Option<Entity> {
+        self.entities.get(&id).copied()
+    }
+
+    pub fn remove(&mut self, id: NetworkId) -> Option<Entity> {
+        self.entities.remove(&id)
+    }
+
+    /// Stops replicating `id` while its entity lives on, e.g. in a pool. Clients are told
+    /// it despawned, and the entity gets a fresh id if it is replicated again.
+    pub fn release(&mut self, id: NetworkId) {
+        if self.entities.remove(&id).is_some() {
+            self.released.push(id);
+        }
+    }
+}
+
+/// Names of the connected clients that passed validation, by client id.
+#[derive(Default)]
+pub struct Players {
+    names: HashMap<u64, String>,
+}
+
+impl Players {
+    pub fn contains_name(&self, name: &str) -> bool {
+        self.names.values().any(|other| other == name)
+    }
+
+    pub fn insert(&mut self, client_id: u64, name: String) {
+        self.names.insert(client_id, name);
+    }
+
+    pub fn remove(&mut self, client_id: u64) -> Option<String> {
+        self.names.remove(&client_id)
+    }
+
+    pub fn name(&self, client_id: u64) -> Option<&str> {
+        self.names.get(&client_id).map(String::as_str)
+    }
+}
+
+/// A client connected and passed validation. Only sent on the server.
+pub struct PlayerJoined {
+    pub client_id: u64,
+    pub name: String,
+    /// Same for every connection of the same player.
+    pub player_id: u64,
+}
+
+/// A validated client disconnected. Only sent on the server.
+pub struct PlayerLeft {
+    pub client_id: u64,
+    pub name: String,
+}
+
 pub struct NetworkingPlugin {
-    exec_type: String,
+    role: NetworkRole,
     server_addr: SocketAddr,
-    _username: Username,
+    username: String,
 }
 
 impl Plugin for NetworkingPlugin {
     fn build(&self, app: &mut App) {
-        match self.exec_type.as_str() {
-            "client" => self.start_client(app),
-            "server" => self.start_server(app),
-
-            _ => {
-                println!("Invalid argument, first one must be \"client\" or \"server\".");
-            }
+        app.init_resource::<NetworkEntities>();
+        // Registered in every role so gameplay systems can read them unconditionally
+        app.add_event::<PlayerJoined>();
+        app.add_event::<PlayerLeft>();
+        app.insert_resource(self.role);
+        match self.role {
+            NetworkRole::Client => self.start_client(app),
+            NetworkRole::Server => self.start_server(app),
+            NetworkRole::Singleplayer => {}
         }
     }
 }
 
-impl NetworkingPlugin {
-    pub fn new(args: &Vec<String>) -> NetworkingPlugin {
-        let mut exec_type = "server".to_string();
-        if let Some(x) = args.get(1) {
-            exec_type = x.clone();
-        }
-        let mut server_addr = "127.0.0.1:5000".parse().unwrap();
-        if let Some(x) = args.get(2) {
-            server_addr = format!("127.0.0.1:{}", x).parse().unwrap();
+/// Why an address given on the command line couldn't be used.
+#[derive(Debug)]
+pub enum AddressError {
+    InvalidPort(String),
+    Unresolved(String),
+}
+
+impl fmt::Display for AddressError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AddressError::InvalidPort(port) => write!(f, "invalid port \"{}\"", port),
+            AddressError::Unresolved(host) => write!(f, "could not resolve \"{}\"", host),
         }
-        let mut _username = Username("player".to_string());
-        if let Some(x) = args.get(3) {
-            _username = Username(x.clone());
+    }
+}
+
+impl std::error::Error for AddressError {}
+
+/// Whether `input` carries its own port, as in `HOST:PORT` or `[IPV6]:PORT`. A bare IPv6
+/// address is full of colons but has no port.
+pub fn address_has_port(input: &str) -> bool {
+    match input.strip_prefix('[') {
+        Some(rest) => matches!(rest.split_once(']'), Some((_, port)) if !port.is_empty()),
+        None => input.parse::<IpAddr>().is_err() && input.contains(':'),
+    }
+}
+
+/// Accepts `HOST`, `HOST:PORT`, `IPV6`, `[IPV6]` or `[IPV6]:PORT`, where the host may be a
+/// name to look up. `default_port` is used when the input doesn't carry one.
+pub fn parse_address(input: &str, default_port: u16) -> Result<SocketAddr, AddressError> {
+    if let Ok(addr) = input.parse::<SocketAddr>() {
+        return Ok(addr);
+    }
+    if let Ok(ip) = input.parse::<IpAddr>() {
+        return Ok(SocketAddr::new(ip, default_port));
+    }
+    // The colons inside the brackets belong to the address, not the port
+    if let Some(rest) = input.strip_prefix('[') {
+        let unresolved = || AddressError::Unresolved(input.to_string());
+        let (host, port) = rest.split_once(']').ok_or_else(unresolved)?;
+        let ip = host.parse::<Ipv6Addr>().map_err(|_| unresolved())?;
+        let port = match port.strip_prefix(':') {
+            Some(port) => port
+                .parse::<u16>()
+                .map_err(|_| AddressError::InvalidPort(port.to_string()))?,
+            None if port.is_empty() => default_port,
+            None => return Err(unresolved()),
+        };
+        return Ok(SocketAddr::new(IpAddr::V6(ip), port));
+    }
+    // A port that didn't parse would otherwise be reported as a lookup failure
+    if let Some((_, port)) = input.rsplit_once(':') {
+        if port.parse::<u16>().is_err() {
+            return Err(AddressError::InvalidPort(port.to_string()));
         }
+    }
+    let resolved = if address_has_port(input) {
+        input.to_socket_addrs()
+    } else {
+        (input, default_port).to_socket_addrs()
+    };
+    resolved
+        .ok()
+        .and_then(|mut addrs| addrs.next())
+        .ok_or_else(|| AddressError::Unresolved(input.to_string()))
+}
+
+/// The wildcard address of the same family as `addr`, to bind sockets on every interface.
+fn unspecified(addr: SocketAddr, port: u16) -> SocketAddr {
+    let ip = match addr {
+        SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    SocketAddr::new(ip, port)
+}
+
+/// Reads this client's player id, saving `fresh` as the id if there is none yet.
+fn load_player_id(fresh: u64) -> u64 {
+    let saved = fs::read_to_string(PLAYER_ID_FILE)
+        .ok()
+        .and_then(|text| text.trim().parse().ok());
+    if let Some(player_id) = saved {
+        return player_id;
+    }
+    // Still usable for this session, the next one just counts as a new player
+    if let Err(err) = fs::write(PLAYER_ID_FILE, fresh.to_string()) {
+        warn!(
+            "Could not save the player id to {}: {}",
+            PLAYER_ID_FILE, err
+        );
+    }
+    fresh
+}
+
+fn bind(addr: SocketAddr) -> UdpSocket {
+    UdpSocket::bind(addr).unwrap_or_else(|err| {
+        eprintln!("Could not bind {}: {}", addr, err);
+        process::exit(1);
+    })
+}
+
+impl NetworkingPlugin {
+    /// `server_addr` is where clients reach the server. The server itself listens on every
+    /// interface, on the same port.
+    pub fn new(role: NetworkRole, server_addr: SocketAddr, username: String) -> NetworkingPlugin {
         NetworkingPlugin {
-            exec_type,
+            role,
             server_addr,
-            _username,
+            username,
         }
     }
 
     fn start_server(&self, app: &mut App) {
         app.add_plugin(RenetServerPlugin);
 
-        let socket = UdpSocket::bind(self.server_addr).unwrap();
-        let connection_config = RenetConnectionConfig::default();
+        let socket = bind(unspecified(self.server_addr, self.server_addr.port()));
+        info!("Listening on port {}", self.server_addr.port());
+        let connection_config = connection_config();
         let server_config = ServerConfig::new(
             64,
             PROTOCOL_ID,
@@ -76,23 +372,47 @@ impl NetworkingPlugin {
             RenetServer::new(current_time, server_config, connection_config, socket).unwrap();
         app.insert_resource(server);
 
+        app.init_resource::<Players>();
+        app.add_system(server::handle_events_system);
         app.add_system(server::send_message_system);
         app.add_system(server::receive_message_system);
-        app.add_system(server::handle_events_system);
+        app.add_system(server::announce_players.after(server::handle_events_system));
+        app.add_system(server::forward_rejections);
+
+        app.init_resource::<ReplicationState>();
+        app.init_resource::<BandwidthLog>();
+        app.add_system(replication::sync_new_clients.after(server::handle_events_system));
+        app.add_system(replication::replicate_spawns);
+        app.add_system(replication::replicate_released);
+        // New clients must be holding replication back before this sends anything
+        app.add_system(
+            replication::replicate_building_changes.after(replication::sync_new_clients),
+        );
+        app.add_system(replication::send_snapshots.after(replication::replicate_released));
+        app.add_system(replication::replicate_combat_text);
+        app.add_system(replication::log_bandwidth);
+        app.add_system(replication::forget_departed_clients);
+        app.add_system_to_stage(DespawnStage, replication::replicate_despawns);
     }
 
     fn start_client(&self, app: &mut App) {
-        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
-        let connection_config = RenetConnectionConfig::default();
+        let socket = bind(unspecified(self.server_addr, 0));
+        let connection_config = connection_config();
         let current_time = SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)
             .unwrap();
         let client_id = current_time.as_millis() as u64;
+        let user_data = ConnectInfo::new(self.username.clone(), load_player_id(client_id))
+            .to_user_data()
+            .unwrap_or_else(|err| {
+                eprintln!("Could not encode the connection info: {}", err);
+                process::exit(1);
+            });
         let authentication = ClientAuthentication::Unsecure {
             client_id,
             protocol_id: PROTOCOL_ID,
             server_addr: self.server_addr,
-            user_data: None,
+            user_data: Some(user_data),
         };
         let client = RenetClient::new(
             current_time,
@@ -105,89 +425,708 @@ impl NetworkingPlugin {
         app.add_plugin(RenetClientPlugin);
         app.insert_resource(client);
 
-        app.add_system(client::send_message_system);
+        app.add_event::<client::BuildingUpdate>();
+        app.add_system(client::forward_commands);
         app.add_system(client::receive_message_system);
+        // Buildings spawned by the same messages only exist once commands have run
+        app.add_system_to_stage(CoreStage::PostUpdate, client::apply_building_updates);
+        app.add_system(client::show_visualizer);
+
+        app.init_resource::<InterpolationSettings>();
+        app.init_resource::<SnapshotClock>();
+        app.init_resource::<SnapshotAssembler>();
+        app.add_system(interpolation::interpolate_entities.after(client::receive_message_system));
 
         app.insert_resource(RenetClientVisualizer::<200>::new(
             RenetVisualizerStyle::default(),
         ));
     }
 }
+mod interpolation;
+pub mod protocol;
+mod replication;
+mod snapshots;
+
 mod server {
-    use bevy::prelude::*;
+    use bevy::{ecs::system::SystemParam, prelude::*};
     use bevy_renet::renet::{RenetServer, ServerEvent};
 
-    pub fn send_message_system(mut server: ResMut<RenetServer>) {
-        let channel_id = 0;
-        // Send a text message for all clients
-        server.broadcast_message(channel_id, "server message".as_bytes().to_vec());
+    use super::{
+        protocol::{self, ClientMessage, ConnectInfo, ServerMessage},
+        replication::ReplicationState,
+        Channel, NetworkEntities, PlayerJoined, PlayerLeft, Players,
+    };
+    use crate::{
+        building::{ActionRejected, BuildRequest, RepairRequest, SellRequest, UpgradeRequest},
+        economy::{Income, PlayerResources, ResourceAmount},
+        enemy::SpawnEnemyRequest,
+        power::SetPowerPriority,
+    };
+
+    /// Events that client commands are turned into, so they go through the same
+    /// validation as local input.
+    #[derive(SystemParam)]
+    pub struct CommandEvents<'w, 's> {
+        build: EventWriter<'w, 's, BuildRequest>,
+        sell: EventWriter<'w, 's, SellRequest>,
+        repair: EventWriter<'w, 's, RepairRequest>,
+        upgrade: EventWriter<'w, 's, UpgradeRequest>,
+        power_priority: EventWriter<'w, 's, SetPowerPriority>,
+        spawn_enemy: EventWriter<'w, 's, SpawnEnemyRequest>,
     }
 
-    pub fn receive_message_system(mut server: ResMut<RenetServer>) {
-        let channel_id = 0;
-        // Send a text message for all clients
-        for client_id in server.clients_id().into_iter() {
-            while let Some(message) = server.receive_message(client_id, channel_id) {
-                // Handle received message
-                info!(
-                    "Received message: {}",
-                    std::str::from_utf8(&message).unwrap()
-                );
-            }
+    pub fn broadcast(server: &mut RenetServer, channel: Channel, message: &ServerMessage) {
+        if let Some(bytes) = channel.encode(message) {
+            server.broadcast_message(channel.id(), bytes);
         }
     }
 
+    pub fn send(
+        server: &mut RenetServer,
+        client_id: u64,
+        channel: Channel,
+        message: &ServerMessage,
+    ) {
+        if let Some(bytes) = channel.encode(message) {
+            server.send_message(client_id, channel.id(), bytes);
+        }
+    }
+
+    /// The one place renet connection events are read. Reads who connected from their user
+    /// data and tells the rest of the game through `PlayerJoined` and `PlayerLeft`. Clients
+    /// running another version, using a name that's taken, or that sent nothing usable, are
+    /// told why and dropped on the next frame, once the message has gone out.
     pub fn handle_events_system(
         mut server: ResMut<RenetServer>,
         mut server_events: EventReader<ServerEvent>,
+        mut players: ResMut<Players>,
+        mut joined: EventWriter<PlayerJoined>,
+        mut left: EventWriter<PlayerLeft>,
+        mut refused: Local<Vec<u64>>,
+    ) {
+        for client_id in refused.drain(..) {
+            server.disconnect(client_id);
+        }
+        for event in server_events.iter() {
+            match event {
+                ServerEvent::ClientConnected(client_id, user_data) => {
+                    let info = ConnectInfo::from_user_data(user_data)
+                        .map_err(|err| format!("unreadable connection info: {}", err))
+                        .and_then(|info| info.validate().map(|_| info))
+                        .and_then(|info| {
+                            // Chat and announcements only say who by name
+                            if players.contains_name(&info.username) {
+                                Err(format!("the name {} is already taken", info.username))
+                            } else {
+                                Ok(info)
+                            }
+                        });
+                    match info {
+                        Ok(info) => {
+                            info!("{} joined as client {}", info.username, client_id);
+                            players.insert(*client_id, info.username.clone());
+                            joined.send(PlayerJoined {
+                                client_id: *client_id,
+                                name: info.username,
+                                player_id: info.player_id,
+                            });
+                        }
+                        Err(reason) => {
+                            warn!("Refusing client {}: {}", client_id, reason);
+                            let message = ServerMessage::Refused(reason);
+                            send(&mut server, *client_id, Channel::Reliable, &message);
+                            refused.push(*client_id);
+                        }
+                    }
+                }
+                ServerEvent::ClientDisconnected(client_id) => {
+                    // Refused clients never joined, so they don't leave either
+                    if let Some(name) = players.remove(*client_id) {
+                        info!("{} left", name);
+                        left.send(PlayerLeft {
+                            client_id: *client_id,
+                            name,
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    /// Broadcasts the stockpile whenever the amounts differ from what was last sent. The
+    /// ledger ticks through `PlayerResources` every frame, so `is_changed` alone would
+    /// resend it constantly. Its summary follows when a new bucket changes it.
+    pub fn send_message_system(
+        mut server: ResMut<RenetServer>,
+        mut replication: ResMut<ReplicationState>,
+        res: Res<PlayerResources>,
+        mut last_sent: Local<Vec<ResourceAmount>>,
+        mut last_income: Local<Vec<Income>>,
+    ) {
+        if !res.is_changed() {
+            return;
+        }
+        let amounts = res.amounts();
+        if amounts != *last_sent {
+            let message = ServerMessage::Resources(amounts.clone());
+            replication.replicate(&mut server, &message);
+            *last_sent = amounts;
+        }
+        if res.ledger.rolled() {
+            let income = res.ledger.per_minute();
+            if income != *last_income {
+                let message = ServerMessage::Income(income.clone());
+                replication.replicate(&mut server, &message);
+                *last_income = income;
+            }
+        }
+    }
+
+    pub fn receive_message_system(
+        mut server: ResMut<RenetServer>,
+        mut commands: CommandEvents,
+        mut replication: ResMut<ReplicationState>,
+        entities: Res<NetworkEntities>,
+        players: Res<Players>,
     ) {
-        while let Some(_event) = server.get_event() {
-            for event in server_events.iter() {
-                match event {
-                    ServerEvent::ClientConnected(id, _user_data) => {
-                        println!("Client {} connected", id);
+        for client_id in server.clients_id().into_iter() {
+            let name = match players.name(client_id) {
+                Some(name) => name.to_string(),
+                // Refused clients are ignored until they are dropped
+                None => continue,
+            };
+            // Clients only ever send commands, chat and acknowledgements, the latter for
+            // snapshots unreliably
+            let mut received = Vec::new();
+            for channel in [Channel::Reliable, Channel::Unreliable] {
+                while let Some(bytes) = server.receive_message(client_id, channel.id()) {
+                    received.push(bytes);
+                }
+            }
+            for bytes in received {
+                let message = match protocol::decode::<ClientMessage>(&bytes) {
+                    Ok(message) => message,
+                    Err(err) => {
+                        warn!(
+                            "Dropping malformed message from client {}: {}",
+                            client_id, err
+                        );
+                        continue;
+                    }
+                };
+                // Commands naming an entity that is already gone are dropped
+                match message {
+                    ClientMessage::Build { kind, tile } => commands.build.send(BuildRequest {
+                        kind,
+                        tile: IVec2::new(tile.0, tile.1),
+                        player: Some(client_id),
+                    }),
+                    ClientMessage::Sell(id) => {
+                        if let Some(building) = entities.get(id) {
+                            commands.sell.send(SellRequest {
+                                building,
+                                player: Some(client_id),
+                            });
+                        }
+                    }
+                    ClientMessage::Repair(id) => {
+                        if let Some(building) = entities.get(id) {
+                            commands.repair.send(RepairRequest {
+                                building,
+                                player: Some(client_id),
+                            });
+                        }
                     }
-                    ServerEvent::ClientDisconnected(id) => {
-                        println!("Client {} disconnected", id);
+                    ClientMessage::Upgrade { building, upgrade } => {
+                        if let Some(building) = entities.get(building) {
+                            commands.upgrade.send(UpgradeRequest {
+                                building,
+                                upgrade,
+                                player: Some(client_id),
+                            });
+                        }
+                    }
+                    ClientMessage::SetPowerPriority { building, priority } => {
+                        if let Some(building) = entities.get(building) {
+                            commands
+                                .power_priority
+                                .send(SetPowerPriority { building, priority });
+                        }
+                    }
+                    ClientMessage::SpawnEnemy { kind, translation } => {
+                        commands.spawn_enemy.send(SpawnEnemyRequest {
+                            kind,
+                            translation: Vec3::from(translation),
+                        })
+                    }
+                    ClientMessage::Chat(mut text) => {
+                        protocol::truncate_chat(&mut text);
+                        let message = ServerMessage::Chat {
+                            from: name.clone(),
+                            text,
+                        };
+                        broadcast(&mut server, Channel::Reliable, &message);
+                    }
+                    ClientMessage::SyncReceived => replication.finish_sync(&mut server, client_id),
+                    ClientMessage::SnapshotReceived(tick) => {
+                        replication.acknowledge(client_id, tick)
                     }
                 }
             }
         }
     }
+
+    pub fn announce_players(
+        mut server: ResMut<RenetServer>,
+        mut joined: EventReader<PlayerJoined>,
+        mut left: EventReader<PlayerLeft>,
+    ) {
+        let joined = joined
+            .iter()
+            .map(|player| format!("{} joined", player.name));
+        let left = left.iter().map(|player| format!("{} left", player.name));
+        for text in joined.chain(left).collect::<Vec<_>>() {
+            let message = ServerMessage::Chat {
+                from: "server".to_string(),
+                text,
+            };
+            broadcast(&mut server, Channel::Reliable, &message);
+        }
+    }
+
+    pub fn forward_rejections(
+        mut server: ResMut<RenetServer>,
+        mut rejections: EventReader<ActionRejected>,
+    ) {
+        for rejection in rejections.iter() {
+            if let Some(client_id) = rejection.player {
+                let message = ServerMessage::Rejected(rejection.reason.clone());
+                send(&mut server, client_id, Channel::Reliable, &message);
+            }
+        }
+    }
 }
 
 mod client {
-    use bevy::prelude::*;
+    use std::process;
+
+    use bevy::{ecs::system::SystemParam, prelude::*};
     use bevy_egui::EguiContext;
     use bevy_renet::renet::RenetClient;
     use renet_visualizer::RenetClientVisualizer;
 
-    pub fn send_message_system(mut client: ResMut<RenetClient>) {
-        let channel_id = 0;
-        // Send a text message to the server
-        client.send_message(
-            channel_id,
-            "client message plpl opaskdopkas pkdopas kpdosk"
-                .as_bytes()
-                .to_vec(),
-        );
+    use super::{
+        interpolation::{Interpolated, SnapshotClock},
+        protocol::{
+            self, ClientMessage, CombatText, EntityKind, EntityState, NetworkId, ServerMessage,
+        },
+        snapshots::SnapshotAssembler,
+        Channel, NetworkEntities,
+    };
+    use crate::{
+        assets::GameAssets,
+        building::{
+            spawn_building, upgrade_building, BuildRequest, Building, RepairRequest, SellRequest,
+            UpgradeRequest, UpgradeTargets,
+        },
+        combat_text::{DamageEvent, RewardEvent},
+        constants::MAX_CHAT_BYTES,
+        economy::PlayerResources,
+        enemy::{Enemy, SpawnEnemyRequest},
+        hp_bar::{DamageDealt, Health, Hit},
+        lifecycle::Despawning,
+        map::{world_to_tile, MapSettings, TileGrid},
+        power::{PowerConsumer, PowerPriority, SetPowerPriority},
+        projectile::ProjectileSpawner,
+    };
+
+    /// Replicated changes to buildings, applied by `apply_building_updates`.
+    pub enum BuildingUpdate {
+        Upgraded {
+            id: NetworkId,
+            upgrade: usize,
+            tier: u32,
+        },
+        PowerPriority {
+            id: NetworkId,
+            priority: PowerPriority,
+        },
+    }
+
+    /// Local input that has to be sent to the server instead of being applied here.
+    #[derive(SystemParam)]
+    pub struct LocalCommands<'w, 's> {
+        build: EventReader<'w, 's, BuildRequest>,
+        sell: EventReader<'w, 's, SellRequest>,
+        repair: EventReader<'w, 's, RepairRequest>,
+        upgrade: EventReader<'w, 's, UpgradeRequest>,
+        power_priority: EventReader<'w, 's, SetPowerPriority>,
+        spawn_enemy: EventReader<'w, 's, SpawnEnemyRequest>,
+    }
+
+    pub fn send(client: &mut RenetClient, channel: Channel, message: &ClientMessage) {
+        let bytes = match message {
+            ClientMessage::Chat(text) if text.len() > MAX_CHAT_BYTES => {
+                let mut text = text.clone();
+                protocol::truncate_chat(&mut text);
+                channel.encode(&ClientMessage::Chat(text))
+            }
+            _ => channel.encode(message),
+        };
+        if let Some(bytes) = bytes {
+            client.send_message(channel.id(), bytes);
+        }
+    }
+
+    pub fn forward_commands(
+        mut client: ResMut<RenetClient>,
+        mut local: LocalCommands,
+        q_ids: Query<&NetworkId>,
+    ) {
+        let mut messages = Vec::new();
+        for request in local.build.iter() {
+            messages.push(ClientMessage::Build {
+                kind: request.kind,
+                tile: (request.tile.x, request.tile.y),
+            });
+        }
+        for request in local.sell.iter() {
+            if let Ok(&id) = q_ids.get(request.building) {
+                messages.push(ClientMessage::Sell(id));
+            }
+        }
+        for request in local.repair.iter() {
+            if let Ok(&id) = q_ids.get(request.building) {
+                messages.push(ClientMessage::Repair(id));
+            }
+        }
+        for request in local.upgrade.iter() {
+            if let Ok(&building) = q_ids.get(request.building) {
+                messages.push(ClientMessage::Upgrade {
+                    building,
+                    upgrade: request.upgrade,
+                });
+            }
+        }
+        for request in local.power_priority.iter() {
+            if let Ok(&building) = q_ids.get(request.building) {
+                messages.push(ClientMessage::SetPowerPriority {
+                    building,
+                    priority: request.priority,
+                });
+            }
+        }
+        for request in local.spawn_enemy.iter() {
+            messages.push(ClientMessage::SpawnEnemy {
+                kind: request.kind,
+                translation: request.translation.to_array(),
+            });
+        }
+        for message in messages {
+            send(&mut client, Channel::Reliable, &message);
+        }
     }
 
     pub fn receive_message_system(
+        mut commands: Commands,
         mut client: ResMut<RenetClient>,
-        mut egui_context: ResMut<EguiContext>,
-        mut visualizer: ResMut<RenetClientVisualizer<200>>,
+        mut entities: ResMut<NetworkEntities>,
+        mut grid: ResMut<TileGrid>,
+        mut map: ResMut<MapSettings>,
+        mut res: ResMut<PlayerResources>,
+        mut projectiles: ProjectileSpawner,
+        q_buildings: Query<&Building>,
+        mut q_replicated: Query<(
+            &mut Transform,
+            Option<&mut Health>,
+            Option<&mut Interpolated>,
+        )>,
+        mut clock: ResMut<SnapshotClock>,
+        mut assembler: ResMut<SnapshotAssembler>,
+        mut building_updates: EventWriter<BuildingUpdate>,
+        mut damage_events: EventWriter<DamageEvent>,
+        mut reward_events: EventWriter<RewardEvent>,
+        assets: Res<GameAssets>,
     ) {
-        let channel_id = 0;
-        while let Some(message) = client.receive_message(channel_id) {
-            info!(
-                "Received message: {}",
-                std::str::from_utf8(&message).unwrap()
-            );
+        let mut messages = Vec::new();
+        for channel in Channel::ALL {
+            while let Some(bytes) = client.receive_message(channel.id()) {
+                match protocol::decode::<ServerMessage>(&bytes) {
+                    // The join sync is handled like the individual messages it replaces
+                    Ok(ServerMessage::Sync {
+                        tick,
+                        map: settings,
+                        spawns,
+                        upgrades,
+                        priorities,
+                        resources,
+                        income,
+                    }) => {
+                        clock.observe(tick);
+                        // The map must match the server's before anything is placed on it
+                        if *map != settings {
+                            *map = settings;
+                            *grid = TileGrid::generate(&settings);
+                        }
+                        messages.extend(spawns.into_iter().map(|(id, kind, translation)| {
+                            ServerMessage::Spawn {
+                                id,
+                                kind,
+                                translation,
+                            }
+                        }));
+                        for (id, bought) in upgrades {
+                            messages.extend(bought.into_iter().enumerate().map(|(i, upgrade)| {
+                                ServerMessage::Upgraded {
+                                    id,
+                                    upgrade,
+                                    tier: i as u32 + 1,
+                                }
+                            }));
+                        }
+                        messages.extend(
+                            priorities.into_iter().map(|(id, priority)| {
+                                ServerMessage::PowerPriority { id, priority }
+                            }),
+                        );
+                        messages.push(ServerMessage::Resources(resources));
+                        messages.push(ServerMessage::Income(income));
+                        send(&mut client, Channel::Reliable, &ClientMessage::SyncReceived);
+                    }
+                    Ok(message) => messages.push(message),
+                    Err(err) => warn!("Dropping malformed message from server: {}", err),
+                }
+            }
+        }
+        for message in messages {
+            match message {
+                ServerMessage::Spawn {
+                    id,
+                    kind,
+                    translation,
+                } => {
+                    let translation = Vec3::from(translation);
+                    let entity = match kind {
+                        EntityKind::Building(kind) => spawn_building(
+                            &mut commands,
+                            kind,
+                            world_to_tile(translation.truncate()),
+                            &mut grid,
+                            &assets,
+                        ),
+                        EntityKind::Enemy(kind) => {
+                            Enemy::new(&mut commands, translation, kind, &assets)
+                        }
+                        // Only drawn here, the server decides what it hits
+                        EntityKind::Projectile(faction) => projectiles.spawn(
+                            translation,
+                            Vec2::ZERO,
+                            Hit {
+                                damage: 0.0,
+                                crit: false,
+                            },
+                            0.0,
+                            faction,
+                        ),
+                    };
+                    commands.entity(entity).insert(id);
+                    if !matches!(kind, EntityKind::Building(_)) {
+                        commands
+                            .entity(entity)
+                            .insert(Interpolated::new(&clock, translation));
+                    }
+                    entities.insert(id, entity);
+                }
+                ServerMessage::Despawn(id) => {
+                    assembler.forget(id);
+                    if let Some(entity) = entities.remove(id) {
+                        if let Ok(building) = q_buildings.get(entity) {
+                            grid.free(building.tile);
+                        }
+                        commands.entity(entity).insert(Despawning);
+                    }
+                }
+                ServerMessage::Upgraded { id, upgrade, tier } => {
+                    building_updates.send(BuildingUpdate::Upgraded { id, upgrade, tier })
+                }
+                ServerMessage::PowerPriority { id, priority } => {
+                    building_updates.send(BuildingUpdate::PowerPriority { id, priority })
+                }
+                ServerMessage::Resources(amounts) => res.set_amounts(&amounts),
+                ServerMessage::Income(income) => res.ledger.set_per_minute(income),
+                ServerMessage::CombatText(texts) => {
+                    for text in texts {
+                        match text {
+                            CombatText::Damage {
+                                position,
+                                health,
+                                shield,
+                                kind,
+                                crit,
+                            } => damage_events.send(DamageEvent {
+                                position: Vec3::from(position),
+                                dealt: DamageDealt { health, shield },
+                                kind,
+                                crit,
+                            }),
+                            CombatText::Reward {
+                                position,
+                                resource,
+                                amount,
+                            } => reward_events.send(RewardEvent {
+                                position: Vec3::from(position),
+                                resource,
+                                amount,
+                            }),
+                        }
+                    }
+                }
+                ServerMessage::Rejected(reason) => warn!("{}", reason),
+                // Nothing works without a connection, so don't leave a dead window open
+                ServerMessage::Refused(reason) => {
+                    eprintln!("Server refused the connection: {}", reason);
+                    process::exit(1);
+                }
+                ServerMessage::Chat { from, text } => info!("[{}] {}", from, text),
+                // Already expanded into the messages above on receipt
+                ServerMessage::Sync { .. } => {}
+                ServerMessage::Snapshot {
+                    tick,
+                    baseline,
+                    part,
+                    parts,
+                    entities: delta,
+                } => {
+                    let states = match assembler.receive(tick, baseline, part, parts, delta) {
+                        Some(states) => states,
+                        None => continue,
+                    };
+                    send(
+                        &mut client,
+                        Channel::Unreliable,
+                        &ClientMessage::SnapshotReceived(tick),
+                    );
+                    clock.observe(tick);
+                    for state in states {
+                        let entity = match entities.get(state.id) {
+                            Some(entity) => entity,
+                            None => continue,
+                        };
+                        if let Ok((mut transform, health, interpolated)) =
+                            q_replicated.get_mut(entity)
+                        {
+                            apply_state(tick, &state, &mut transform, health, interpolated);
+                        }
+                    }
+                }
+            }
         }
+    }
 
+    pub fn apply_building_updates(
+        mut updates: EventReader<BuildingUpdate>,
+        entities: Res<NetworkEntities>,
+        mut q_buildings: UpgradeTargets,
+        mut q_consumers: Query<&mut PowerConsumer>,
+    ) {
+        for update in updates.iter() {
+            match *update {
+                BuildingUpdate::Upgraded { id, upgrade, tier } => {
+                    let entity = match entities.get(id) {
+                        Some(entity) => entity,
+                        None => continue,
+                    };
+                    // An upgrade bought while this client joined arrives with the sync and
+                    // again from the held back replication, it only applies once
+                    let next = matches!(
+                        q_buildings.get_component::<Building>(entity),
+                        Ok(building) if building.tier + 1 == tier
+                    );
+                    if next {
+                        upgrade_building(&mut q_buildings, entity, upgrade);
+                    }
+                }
+                BuildingUpdate::PowerPriority { id, priority } => {
+                    if let Some(mut consumer) = entities
+                        .get(id)
+                        .and_then(|entity| q_consumers.get_mut(entity).ok())
+                    {
+                        consumer.priority = priority;
+                    }
+                }
+            }
+        }
+    }
+
+    fn apply_state(
+        tick: u32,
+        state: &EntityState,
+        transform: &mut Transform,
+        health: Option<Mut<Health>>,
+        interpolated: Option<Mut<Interpolated>>,
+    ) {
+        let translation = state.translation.map(Vec3::from);
+        if let Some(mut interpolated) = interpolated {
+            // Moved smoothly by `interpolate_entities` instead
+            interpolated.push(tick, translation, state.rotation);
+        } else {
+            if let Some(translation) = translation {
+                transform.translation = translation;
+            }
+            if let Some(rotation) = state.rotation {
+                transform.rotation = Quat::from_rotation_z(rotation);
+            }
+        }
+        if let (Some((current, max)), Some(mut health)) = (state.health, health) {
+            health.current = current;
+            health.max = max;
+        }
+    }
+
+    pub fn show_visualizer(
+        client: Res<RenetClient>,
+        mut egui_context: ResMut<EguiContext>,
+        mut visualizer: ResMut<RenetClientVisualizer<200>>,
+    ) {
         visualizer.add_network_info(client.network_info());
 
         visualizer.show_window(egui_context.ctx_mut());
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOCALHOST_V6: IpAddr = IpAddr::V6(Ipv6Addr::LOCALHOST);
+
+    #[test]
+    fn bracketed_ipv6_takes_the_default_port() {
+        let addr = parse_address("[::1]", DEFAULT_PORT).unwrap();
+        assert_eq!(addr, SocketAddr::new(LOCALHOST_V6, DEFAULT_PORT));
+        assert!(!address_has_port("[::1]"));
+    }
+
+    #[test]
+    fn bracketed_ipv6_with_a_port() {
+        // A default that differs from the given port, to tell them apart
+        let addr = parse_address("[::1]:5000", 7000).unwrap();
+        assert_eq!(addr, SocketAddr::new(LOCALHOST_V6, 5000));
+        assert!(address_has_port("[::1]:5000"));
+    }
+
+    #[test]
+    fn bare_ipv6_has_no_port() {
+        let addr = parse_address("::1", DEFAULT_PORT).unwrap();
+        assert_eq!(addr, SocketAddr::new(LOCALHOST_V6, DEFAULT_PORT));
+        assert!(!address_has_port("::1"));
+    }
+
+    #[test]
+    fn bracketed_ipv6_with_a_bad_port() {
+        assert!(matches!(
+            parse_address("[::1]:port", DEFAULT_PORT),
+            Err(AddressError::InvalidPort(port)) if port == "port"
+        ));
+    }
+}
@@ -0,0 +1,147 @@
+//! Client side smoothing of replicated movement. Entities are drawn a little in the past,
+//! between the two snapshots around that moment, instead of jumping at snapshot rate.
+
+use std::collections::VecDeque;
+
+use bevy::prelude::*;
+
+use crate::constants::*;
+
+/// How far behind the newest snapshot entities are drawn, and how long they may keep moving
+/// on their own when snapshots stop arriving.
+pub struct InterpolationSettings {
+    pub delay: f32,
+    pub max_extrapolation: f32,
+}
+
+impl Default for InterpolationSettings {
+    fn default() -> Self {
+        InterpolationSettings {
+            delay: INTERPOLATION_DELAY,
+            max_extrapolation: MAX_EXTRAPOLATION,
+        }
+    }
+}
+
+/// Estimate of the server's simulation time, in seconds since it started sending snapshots.
+#[derive(Default)]
+pub struct SnapshotClock {
+    server_time: f64,
+    newest: f64,
+}
+
+impl SnapshotClock {
+    pub fn tick_time(tick: u32) -> f64 {
+        tick as f64 / SNAPSHOT_RATE as f64
+    }
+
+    /// Nudges the clock towards a newer snapshot's time, forwards or back, so it follows the
+    /// server even when the two run at slightly different speeds. Late snapshots are ignored
+    /// and a large difference, like the first snapshot, is jumped over at once.
+    pub fn observe(&mut self, tick: u32) {
+        let time = Self::tick_time(tick);
+        if time <= self.newest {
+            return;
+        }
+        self.newest = time;
+        let error = time - self.server_time;
+        if error.abs() > CLOCK_SNAP_THRESHOLD {
+            self.server_time = time;
+        } else {
+            self.server_time += error * CLOCK_CORRECTION;
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Sample {
+    time: f64,
+    translation: Vec3,
+    rotation: f32,
+}
+
+/// Recent server states of an entity that moves.
+#[derive(Component)]
+pub struct Interpolated {
+    samples: VecDeque<Sample>,
+}
+
+impl Interpolated {
+    pub fn new(clock: &SnapshotClock, translation: Vec3) -> Self {
+        let mut samples = VecDeque::new();
+        samples.push_back(Sample {
+            time: clock.server_time,
+            translation,
+            rotation: 0.0,
+        });
+        Interpolated { samples }
+    }
+
+    /// Records the state at `tick`. Missing fields keep their previous value and samples
+    /// arriving out of order are dropped.
+    pub fn push(&mut self, tick: u32, translation: Option<Vec3>, rotation: Option<f32>) {
+        let time = SnapshotClock::tick_time(tick);
+        let last = match self.samples.back_mut() {
+            Some(last) if last.time == time => {
+                last.translation = translation.unwrap_or(last.translation);
+                last.rotation = rotation.unwrap_or(last.rotation);
+                return;
+            }
+            Some(last) if last.time > time => return,
+            Some(last) => *last,
+            None => return,
+        };
+        self.samples.push_back(Sample {
+            time,
+            translation: translation.unwrap_or(last.translation),
+            rotation: rotation.unwrap_or(last.rotation),
+        });
+        while self.samples.len() > SNAPSHOT_BUFFER_SIZE {
+            self.samples.pop_front();
+        }
+    }
+
+    fn sample_at(&mut self, time: f64, max_extrapolation: f64) -> Sample {
+        // Keep one sample at or before `time` to interpolate from
+        while self.samples.len() > 2 && self.samples[1].time <= time {
+            self.samples.pop_front();
+        }
+        let first = self.samples[0];
+        let second = match self.samples.get(1) {
+            Some(&second) => second,
+            None => return first,
+        };
+        if time <= first.time {
+            return first;
+        }
+        // Past the newest sample keep going in the same direction, but not forever
+        let time = time.min(second.time + max_extrapolation);
+        let t = ((time - first.time) / (second.time - first.time)) as f32;
+        Sample {
+            time,
+            translation: first.translation.lerp(second.translation, t),
+            rotation: lerp_angle(first.rotation, second.rotation, t),
+        }
+    }
+}
+
+fn lerp_angle(from: f32, to: f32, t: f32) -> f32 {
+    use std::f32::consts::{PI, TAU};
+    let diff = (to - from + PI).rem_euclid(TAU) - PI;
+    from + diff * t
+}
+
+pub fn interpolate_entities(
+    mut clock: ResMut<SnapshotClock>,
+    settings: Res<InterpolationSettings>,
+    mut q_interpolated: Query<(&mut Interpolated, &mut Transform)>,
+    time: Res<Time>,
+) {
+    clock.server_time += time.delta_seconds_f64();
+    let render_time = clock.server_time - settings.delay as f64;
+    for (mut interpolated, mut transform) in q_interpolated.iter_mut() {
+        let sample = interpolated.sample_at(render_time, settings.max_extrapolation as f64);
+        transform.translation = sample.translation;
+        transform.rotation = Quat::from_rotation_z(sample.rotation);
+    }
+}
@@ -0,0 +1,456 @@
+//! Messages exchanged between client and server, encoded with bincode.
+
+use bevy::prelude::Component;
+use bevy_renet::renet::NETCODE_USER_DATA_BYTES;
+use bincode::Options;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    building::BuildingKind,
+    combat_text::DamageKind,
+    constants::{BLOCK_MAX_MESSAGE_SIZE, MAX_CHAT_BYTES, MAX_USERNAME_LENGTH},
+    economy::{Amount, Income, ResourceKind},
+    enemy::EnemyKind,
+    map::MapSettings,
+    power::PowerPriority,
+    projectile::Faction,
+};
+
+/// Bumped whenever a message changes shape, so mismatched builds refuse to talk.
+pub const PROTOCOL_VERSION: u32 = 6;
+pub const GAME_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Who is connecting, sent in the renet user data when the connection is made.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ConnectInfo {
+    pub username: String,
+    /// Stays the same when the player reconnects, unlike the renet client id.
+    pub player_id: u64,
+    pub game_version: String,
+    pub protocol_version: u32,
+}
+
+impl ConnectInfo {
+    pub fn new(username: String, player_id: u64) -> Self {
+        ConnectInfo {
+            username,
+            player_id,
+            game_version: GAME_VERSION.to_string(),
+            protocol_version: PROTOCOL_VERSION,
+        }
+    }
+
+    pub fn to_user_data(&self) -> Result<[u8; NETCODE_USER_DATA_BYTES], bincode::Error> {
+        let bytes = bincode::DefaultOptions::new()
+            .with_limit(NETCODE_USER_DATA_BYTES as u64)
+            .serialize(self)?;
+        let mut user_data = [0; NETCODE_USER_DATA_BYTES];
+        user_data[..bytes.len()].copy_from_slice(&bytes);
+        Ok(user_data)
+    }
+
+    /// Trailing padding after the encoded fields is ignored.
+    pub fn from_user_data(
+        user_data: &[u8; NETCODE_USER_DATA_BYTES],
+    ) -> Result<Self, bincode::Error> {
+        bincode::DefaultOptions::new()
+            .with_limit(NETCODE_USER_DATA_BYTES as u64)
+            .allow_trailing_bytes()
+            .deserialize(user_data)
+    }
+
+    /// Why the server won't accept this client, if it won't.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.protocol_version != PROTOCOL_VERSION {
+            return Err(format!(
+                "protocol version {} does not match the server's {}",
+                self.protocol_version, PROTOCOL_VERSION
+            ));
+        }
+        if self.game_version != GAME_VERSION {
+            return Err(format!(
+                "game version {} does not match the server's {}",
+                self.game_version, GAME_VERSION
+            ));
+        }
+        validate_username(&self.username)
+    }
+}
+
+pub fn validate_username(username: &str) -> Result<(), String> {
+    if username.trim().is_empty() {
+        return Err("username must not be empty".to_string());
+    }
+    if username.chars().count() > MAX_USERNAME_LENGTH {
+        return Err(format!(
+            "username must be at most {} characters",
+            MAX_USERNAME_LENGTH
+        ));
+    }
+    Ok(())
+}
+
+/// Cuts chat text down to `MAX_CHAT_BYTES`, on a character boundary.
+pub fn truncate_chat(text: &mut String) {
+    if text.len() <= MAX_CHAT_BYTES {
+        return;
+    }
+    let mut end = MAX_CHAT_BYTES;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    text.truncate(end);
+}
+
+/// Identifies a replicated entity on every machine, since `Entity` ids differ between them.
+#[derive(Component, Clone, Copy, PartialEq, Eq, Hash, Debug, Default, Serialize, Deserialize)]
+pub struct NetworkId(pub u64);
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum EntityKind {
+    Enemy(EnemyKind),
+    Building(BuildingKind),
+    Projectile(Faction),
+}
+
+/// Position and health of one entity at snapshot time. Fields that didn't change since
+/// the snapshot's baseline are left out.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct EntityState {
+    pub id: NetworkId,
+    pub translation: Option<[f32; 3]>,
+    /// Rotation around the z axis in radians.
+    pub rotation: Option<f32>,
+    /// Current and maximum health.
+    pub health: Option<(f32, f32)>,
+}
+
+impl EntityState {
+    /// Only the fields of `self` that differ noticeably from `previous`.
+    pub fn delta_from(&self, previous: &EntityState) -> EntityState {
+        fn changed<T: PartialEq + Copy>(
+            current: Option<T>,
+            previous: Option<T>,
+            close: impl Fn(T, T) -> bool,
+        ) -> Option<T> {
+            match (current, previous) {
+                (Some(current), Some(previous)) if close(current, previous) => None,
+                (current, _) => current,
+            }
+        }
+        EntityState {
+            id: self.id,
+            translation: changed(self.translation, previous.translation, |a, b| {
+                a.iter().zip(b).all(|(a, b)| (a - b).abs() < 0.001)
+            }),
+            rotation: changed(self.rotation, previous.rotation, |a, b| {
+                (a - b).abs() < 0.01
+            }),
+            health: changed(self.health, previous.health, |a, b| a == b),
+        }
+    }
+
+    /// Takes over every field `delta` carries.
+    pub fn merge(&mut self, delta: &EntityState) {
+        self.translation = delta.translation.or(self.translation);
+        self.rotation = delta.rotation.or(self.rotation);
+        self.health = delta.health.or(self.health);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.translation.is_none() && self.rotation.is_none() && self.health.is_none()
+    }
+}
+
+/// A floating number to show where something happened.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum CombatText {
+    Damage {
+        position: [f32; 3],
+        health: f32,
+        shield: f32,
+        kind: DamageKind,
+        crit: bool,
+    },
+    Reward {
+        position: [f32; 3],
+        resource: ResourceKind,
+        amount: Amount,
+    },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum ClientMessage {
+    Build {
+        kind: BuildingKind,
+        tile: (i32, i32),
+    },
+    Sell(NetworkId),
+    Repair(NetworkId),
+    Upgrade {
+        building: NetworkId,
+        upgrade: usize,
+    },
+    SetPowerPriority {
+        building: NetworkId,
+        priority: PowerPriority,
+    },
+    SpawnEnemy {
+        kind: EnemyKind,
+        translation: [f32; 3],
+    },
+    Chat(String),
+    /// The join sync has been applied, replication can continue from there.
+    SyncReceived,
+    /// Every part of this snapshot arrived, the server may build deltas against it.
+    SnapshotReceived(u32),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum ServerMessage {
+    Chat {
+        from: String,
+        text: String,
+    },
+    Spawn {
+        id: NetworkId,
+        kind: EntityKind,
+        translation: [f32; 3],
+    },
+    Despawn(NetworkId),
+    /// The building bought upgrade number `upgrade`, which left it at `tier`.
+    Upgraded {
+        id: NetworkId,
+        upgrade: usize,
+        tier: u32,
+    },
+    PowerPriority {
+        id: NetworkId,
+        priority: PowerPriority,
+    },
+    /// One part of the state at `tick`, as changes against the snapshot at `baseline`
+    /// that the client acknowledged, or in full without one.
+    Snapshot {
+        tick: u32,
+        baseline: Option<u32>,
+        part: u16,
+        parts: u16,
+        entities: Vec<EntityState>,
+    },
+    Resources(Vec<(ResourceKind, Amount)>),
+    /// The ledger's summary of the last minute, sent whenever it changes.
+    Income(Vec<Income>),
+    /// Damage and rewards since the last one, purely cosmetic so it may be lost.
+    CombatText(Vec<CombatText>),
+    /// One of the receiving client's commands was refused.
+    Rejected(String),
+    /// The connection was refused and is about to be closed.
+    Refused(String),
+    /// Everything a joining client needs, sent once in one piece. Entity state follows in
+    /// full snapshots, as the client has no baseline yet.
+    Sync {
+        tick: u32,
+        map: MapSettings,
+        spawns: Vec<(NetworkId, EntityKind, [f32; 3])>,
+        /// Upgrades each building bought so far, in order.
+        upgrades: Vec<(NetworkId, Vec<usize>)>,
+        /// Consumers whose priority isn't the default.
+        priorities: Vec<(NetworkId, PowerPriority)>,
+        resources: Vec<(ResourceKind, Amount)>,
+        income: Vec<Income>,
+    },
+}
+
+fn options() -> impl Options {
+    // No channel carries anything larger, and the limit keeps a corrupt length prefix from
+    // making the decoder allocate gigabytes
+    bincode::DefaultOptions::new().with_limit(BLOCK_MAX_MESSAGE_SIZE)
+}
+
+pub fn encode<T: Serialize>(message: &T) -> Result<Vec<u8>, bincode::Error> {
+    options().serialize(message)
+}
+
+pub fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, bincode::Error> {
+    options().deserialize(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{Rng, SeedableRng};
+    use rand_chacha::ChaCha8Rng;
+
+    use super::*;
+    use crate::constants::{DEFAULT_SEED, DEFAULT_USERNAME, MIN_MAP_SIZE};
+
+    fn info(username: &str) -> ConnectInfo {
+        ConnectInfo::new(username.to_string(), 1)
+    }
+
+    #[test]
+    fn connect_info_survives_user_data() {
+        let user_data = info(DEFAULT_USERNAME).to_user_data().unwrap();
+        let decoded = ConnectInfo::from_user_data(&user_data).unwrap();
+        assert_eq!(decoded.username, DEFAULT_USERNAME);
+        assert!(decoded.validate().is_ok());
+    }
+
+    #[test]
+    fn connect_info_too_large_for_user_data_is_an_error() {
+        let username = "a".repeat(NETCODE_USER_DATA_BYTES);
+        assert!(info(&username).to_user_data().is_err());
+    }
+
+    #[test]
+    fn connect_info_rejects_other_versions() {
+        let mut other = info(DEFAULT_USERNAME);
+        other.protocol_version += 1;
+        assert!(other.validate().is_err());
+
+        let mut other = info(DEFAULT_USERNAME);
+        other.game_version.push_str("-other");
+        assert!(other.validate().is_err());
+
+        // Blank user data, e.g. from a client that sent none
+        let blank = ConnectInfo::from_user_data(&[0; NETCODE_USER_DATA_BYTES]).unwrap();
+        assert!(blank.validate().is_err());
+    }
+
+    #[test]
+    fn connect_info_rejects_bad_usernames() {
+        assert!(info("").validate().is_err());
+        assert!(info("   ").validate().is_err());
+        assert!(info(&"a".repeat(MAX_USERNAME_LENGTH + 1))
+            .validate()
+            .is_err());
+        // The limit counts characters, not bytes
+        assert!(info(&"é".repeat(MAX_USERNAME_LENGTH)).validate().is_ok());
+    }
+
+    #[test]
+    fn delta_merges_back_into_the_full_state() {
+        let previous = EntityState {
+            id: NetworkId(1),
+            translation: Some([0.0, 0.0, 1.0]),
+            rotation: Some(0.5),
+            health: Some((10.0, 10.0)),
+        };
+        let current = EntityState {
+            translation: Some([4.0, -2.0, 1.0]),
+            // Below the threshold, not worth sending
+            rotation: Some(0.5005),
+            health: Some((7.0, 10.0)),
+            ..previous.clone()
+        };
+        let delta = current.delta_from(&previous);
+        assert_eq!(delta.id, current.id);
+        assert_eq!(delta.translation, current.translation);
+        assert_eq!(delta.rotation, None);
+        assert_eq!(delta.health, current.health);
+
+        let mut merged = previous.clone();
+        merged.merge(&delta);
+        assert_eq!(merged.translation, current.translation);
+        assert_eq!(merged.rotation, previous.rotation);
+        assert_eq!(merged.health, current.health);
+        assert!(merged.delta_from(&current).is_empty());
+    }
+
+    #[test]
+    fn delta_without_previous_fields_is_in_full() {
+        let current = EntityState {
+            id: NetworkId(1),
+            translation: Some([1.0, 2.0, 3.0]),
+            rotation: Some(0.0),
+            health: Some((1.0, 1.0)),
+        };
+        let previous = EntityState {
+            id: NetworkId(1),
+            ..Default::default()
+        };
+        let delta = current.delta_from(&previous);
+        assert_eq!(delta.translation, current.translation);
+        assert_eq!(delta.rotation, current.rotation);
+        assert_eq!(delta.health, current.health);
+    }
+
+    fn sync() -> ServerMessage {
+        ServerMessage::Sync {
+            tick: 7,
+            map: MapSettings::new(MIN_MAP_SIZE, DEFAULT_SEED),
+            spawns: vec![(
+                NetworkId(3),
+                EntityKind::Enemy(EnemyKind::Melee),
+                [1.0, 2.0, 3.0],
+            )],
+            upgrades: vec![(NetworkId(4), vec![0, 1])],
+            priorities: vec![(NetworkId(4), PowerPriority::High)],
+            resources: Vec::new(),
+            income: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn messages_survive_encoding() {
+        let bytes = encode(&sync()).unwrap();
+        match decode::<ServerMessage>(&bytes).unwrap() {
+            ServerMessage::Sync {
+                tick,
+                spawns,
+                upgrades,
+                priorities,
+                ..
+            } => {
+                assert_eq!(tick, 7);
+                assert_eq!(spawns.len(), 1);
+                assert_eq!(upgrades, vec![(NetworkId(4), vec![0, 1])]);
+                assert_eq!(priorities, vec![(NetworkId(4), PowerPriority::High)]);
+            }
+            other => panic!("decoded {:?}", other),
+        }
+    }
+
+    #[test]
+    fn truncated_messages_are_errors() {
+        let bytes = encode(&sync()).unwrap();
+        for len in 0..bytes.len() {
+            assert!(decode::<ServerMessage>(&bytes[..len]).is_err());
+        }
+    }
+
+    #[test]
+    fn garbage_is_not_a_message() {
+        // Decodes to a variant index far past the last one
+        assert!(decode::<ClientMessage>(&[0xff; 64]).is_err());
+        assert!(decode::<ServerMessage>(&[0xff; 64]).is_err());
+
+        // Anything at all may come off the wire, decoding must not panic on it
+        let mut rng = ChaCha8Rng::seed_from_u64(DEFAULT_SEED);
+        let mut bytes = [0; 256];
+        for _ in 0..1000 {
+            let len = rng.gen_range(0..bytes.len());
+            rng.fill(&mut bytes[..len]);
+            let _ = decode::<ClientMessage>(&bytes[..len]);
+            let _ = decode::<ServerMessage>(&bytes[..len]);
+        }
+    }
+
+    #[test]
+    fn oversized_length_prefix_is_an_error() {
+        // Chat with an empty string ends in the string's length, a single zero byte
+        let mut bytes = encode(&ClientMessage::Chat(String::new())).unwrap();
+        assert_eq!(bytes.pop(), Some(0));
+        // Varint marker for a u64, then the largest length there is
+        bytes.push(253);
+        bytes.extend_from_slice(&u64::MAX.to_le_bytes());
+        assert!(decode::<ClientMessage>(&bytes).is_err());
+
+        // Just past the limit, with the bytes actually there
+        let mut bytes = encode(&ClientMessage::Chat(String::new())).unwrap();
+        bytes.pop();
+        bytes.push(252);
+        bytes.extend_from_slice(&(BLOCK_MAX_MESSAGE_SIZE as u32 + 1).to_le_bytes());
+        bytes.resize(bytes.len() + BLOCK_MAX_MESSAGE_SIZE as usize + 1, b'a');
+        assert!(decode::<ClientMessage>(&bytes).is_err());
+    }
+}
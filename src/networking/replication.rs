@@ -0,0 +1,644 @@
+//! Server side replication: tells clients which entities exist and where they are.
+
+use std::collections::VecDeque;
+
+use bevy::{prelude::*, utils::HashMap};
+use bevy_renet::renet::RenetServer;
+
+use super::{
+    protocol::{CombatText, EntityKind, EntityState, NetworkId, ServerMessage},
+    server::{broadcast, send},
+    Channel, NetworkEntities, PlayerJoined, PlayerLeft,
+};
+use crate::{
+    building::{Building, BuildingUpgraded},
+    combat_text::{DamageEvent, RewardEvent},
+    constants::*,
+    economy::PlayerResources,
+    enemy::Enemy,
+    hp_bar::Health,
+    lifecycle::Despawning,
+    map::MapSettings,
+    power::{PowerConsumer, PowerPriority, SetPowerPriority},
+    projectile::Projectile,
+};
+
+/// Recent snapshots and what each client acknowledged of them, so snapshots only carry
+/// changes a client hasn't seen.
+pub struct ReplicationState {
+    tick: u32,
+    timer: Timer,
+    /// Full state of every replicated entity at each recent tick, oldest first.
+    history: VecDeque<(u32, HashMap<NetworkId, EntityState>)>,
+    /// Newest snapshot each client received completely.
+    acked: HashMap<u64, u32>,
+    /// Reliable replication held back from clients that haven't confirmed their join sync
+    /// yet. The sync travels on the block channel and could otherwise be overtaken, e.g. by
+    /// the despawn of an entity it contains.
+    held: HashMap<u64, Vec<ServerMessage>>,
+}
+
+impl Default for ReplicationState {
+    fn default() -> Self {
+        ReplicationState {
+            tick: 0,
+            timer: Timer::from_seconds(1.0 / SNAPSHOT_RATE, true),
+            history: VecDeque::new(),
+            acked: HashMap::default(),
+            held: HashMap::default(),
+        }
+    }
+}
+
+impl ReplicationState {
+    /// Sends `message` reliably to every client, queueing it for those still syncing.
+    pub fn replicate(&mut self, server: &mut RenetServer, message: &ServerMessage) {
+        let bytes = match Channel::Reliable.encode(message) {
+            Some(bytes) => bytes,
+            None => return,
+        };
+        for client_id in server.clients_id() {
+            match self.held.get_mut(&client_id) {
+                Some(queue) => queue.push(message.clone()),
+                None => server.send_message(client_id, Channel::Reliable.id(), bytes.clone()),
+            }
+        }
+    }
+
+    /// The client has applied its join sync, so what was held back can follow.
+    pub fn finish_sync(&mut self, server: &mut RenetServer, client_id: u64) {
+        for message in self.held.remove(&client_id).unwrap_or_default() {
+            send(server, client_id, Channel::Reliable, &message);
+        }
+    }
+
+    /// Acknowledgements travel unreliably, so an older one may arrive late.
+    pub fn acknowledge(&mut self, client_id: u64, tick: u32) {
+        let acked = self.acked.entry(client_id).or_insert(tick);
+        *acked = (*acked).max(tick);
+    }
+
+    fn is_syncing(&self, client_id: u64) -> bool {
+        self.held.contains_key(&client_id)
+    }
+
+    /// The acknowledged snapshot to build `client_id`'s deltas against, if still kept.
+    fn baseline(&self, client_id: u64) -> Option<(u32, &HashMap<NetworkId, EntityState>)> {
+        let acked = *self.acked.get(&client_id)?;
+        self.history
+            .iter()
+            .find(|(tick, _)| *tick == acked)
+            .map(|(tick, states)| (*tick, states))
+    }
+}
+
+/// Periodically logs how much each client is being sent.
+pub struct BandwidthLog {
+    timer: Timer,
+    snapshot_bytes: HashMap<u64, usize>,
+}
+
+impl Default for BandwidthLog {
+    fn default() -> Self {
+        BandwidthLog {
+            timer: Timer::from_seconds(BANDWIDTH_LOG_INTERVAL, true),
+            snapshot_bytes: HashMap::default(),
+        }
+    }
+}
+
+type Replicated = Or<(With<Building>, With<Enemy>, With<Projectile>)>;
+
+fn entity_kind(
+    building: Option<&Building>,
+    enemy: Option<&Enemy>,
+    projectile: Option<&Projectile>,
+) -> Option<EntityKind> {
+    building
+        .map(|building| EntityKind::Building(building.kind))
+        .or_else(|| enemy.map(|enemy| EntityKind::Enemy(enemy.kind())))
+        .or_else(|| projectile.map(|projectile| EntityKind::Projectile(projectile.faction())))
+}
+
+fn capture(id: NetworkId, transform: &Transform, health: Option<&Health>) -> EntityState {
+    let (_, _, rotation) = transform.rotation.to_euler(EulerRot::XYZ);
+    EntityState {
+        id,
+        translation: Some(transform.translation.to_array()),
+        rotation: Some(rotation),
+        health: health.map(|health| (health.current, health.max)),
+    }
+}
+
+pub fn replicate_spawns(
+    mut commands: Commands,
+    mut server: ResMut<RenetServer>,
+    mut entities: ResMut<NetworkEntities>,
+    mut state: ResMut<ReplicationState>,
+    q_new: Query<
+        (
+            Entity,
+            &Transform,
+            &Visibility,
+            Option<&Building>,
+            Option<&Enemy>,
+            Option<&Projectile>,
+        ),
+        (Without<NetworkId>, Replicated),
+    >,
+) {
+    for (entity, transform, visibility, building, enemy, projectile) in q_new.iter() {
+        // Hidden projectiles are sitting in the pool
+        if !visibility.is_visible {
+            continue;
+        }
+        if let Some(kind) = entity_kind(building, enemy, projectile) {
+            let id = entities.allocate(entity);
+            commands.entity(entity).insert(id);
+            let message = ServerMessage::Spawn {
+                id,
+                kind,
+                translation: transform.translation.to_array(),
+            };
+            state.replicate(&mut server, &message);
+        }
+    }
+}
+
+/// Pooled projectiles never despawn, they release their id when hidden and come back under
+/// a fresh one when fired again.
+pub fn replicate_released(
+    mut server: ResMut<RenetServer>,
+    mut entities: ResMut<NetworkEntities>,
+    mut state: ResMut<ReplicationState>,
+) {
+    for id in std::mem::take(&mut entities.released) {
+        state.replicate(&mut server, &ServerMessage::Despawn(id));
+    }
+}
+
+/// Upgrades and power priorities change what a building does, so clients apply them too.
+/// Priorities are only set on consumers, which is all `set_power_priorities` checks.
+pub fn replicate_building_changes(
+    mut server: ResMut<RenetServer>,
+    mut state: ResMut<ReplicationState>,
+    mut upgraded: EventReader<BuildingUpgraded>,
+    mut priorities: EventReader<SetPowerPriority>,
+    q_ids: Query<&NetworkId>,
+    q_consumers: Query<&NetworkId, With<PowerConsumer>>,
+) {
+    for event in upgraded.iter() {
+        if let Ok(&id) = q_ids.get(event.building) {
+            let message = ServerMessage::Upgraded {
+                id,
+                upgrade: event.upgrade,
+                tier: event.tier,
+            };
+            state.replicate(&mut server, &message);
+        }
+    }
+    for event in priorities.iter() {
+        if let Ok(&id) = q_consumers.get(event.building) {
+            let message = ServerMessage::PowerPriority {
+                id,
+                priority: event.priority,
+            };
+            state.replicate(&mut server, &message);
+        }
+    }
+}
+
+pub fn replicate_despawns(
+    mut server: ResMut<RenetServer>,
+    mut entities: ResMut<NetworkEntities>,
+    mut state: ResMut<ReplicationState>,
+    q_despawning: Query<&NetworkId, With<Despawning>>,
+) {
+    for &id in q_despawning.iter() {
+        entities.remove(id);
+        state.replicate(&mut server, &ServerMessage::Despawn(id));
+    }
+}
+
+/// Sends every client what changed since the last snapshot it acknowledged, or the full
+/// state when there is none. A lost snapshot is never acknowledged, so its changes are
+/// repeated until one gets through.
+pub fn send_snapshots(
+    mut server: ResMut<RenetServer>,
+    mut state: ResMut<ReplicationState>,
+    mut log: ResMut<BandwidthLog>,
+    q_replicated: Query<
+        (&NetworkId, &Transform, &Visibility, Option<&Health>),
+        Without<Despawning>,
+    >,
+    time: Res<Time>,
+) {
+    state.timer.tick(time.delta());
+    if !state.timer.just_finished() {
+        return;
+    }
+    // A long frame can span several periods, the tick must keep up with the clock
+    state.tick += state.timer.times_finished();
+    let tick = state.tick;
+
+    let mut current = HashMap::default();
+    for (&id, transform, visibility, health) in q_replicated.iter() {
+        // Just pooled, its id is removed once commands apply
+        if visibility.is_visible {
+            current.insert(id, capture(id, transform, health));
+        }
+    }
+    for client_id in server.clients_id() {
+        // Syncing clients don't know the entities yet
+        if state.is_syncing(client_id) {
+            continue;
+        }
+        let baseline = state.baseline(client_id);
+        let entities: Vec<_> = current
+            .values()
+            .filter_map(|current| {
+                let delta = match baseline.and_then(|(_, states)| states.get(&current.id)) {
+                    Some(previous) => current.delta_from(previous),
+                    None => current.clone(),
+                };
+                if delta.is_empty() {
+                    None
+                } else {
+                    Some(delta)
+                }
+            })
+            .collect();
+        // Split so every part fits in a single unreliable message, an empty snapshot still
+        // tells the client nothing changed
+        let chunks: Vec<_> = if entities.is_empty() {
+            vec![&entities[..]]
+        } else {
+            entities.chunks(SNAPSHOT_CHUNK_SIZE).collect()
+        };
+        if chunks.len() > MAX_SNAPSHOT_PARTS as usize {
+            error!(
+                "Skipping snapshot {} for client {}, {} entities need more than {} parts",
+                tick,
+                client_id,
+                entities.len(),
+                MAX_SNAPSHOT_PARTS
+            );
+            continue;
+        }
+        for (part, chunk) in chunks.iter().enumerate() {
+            let message = ServerMessage::Snapshot {
+                tick,
+                baseline: baseline.map(|(tick, _)| tick),
+                part: part as u16,
+                parts: chunks.len() as u16,
+                entities: chunk.to_vec(),
+            };
+            if let Some(bytes) = Channel::Unreliable.encode(&message) {
+                *log.snapshot_bytes.entry(client_id).or_default() += bytes.len();
+                server.send_message(client_id, Channel::Unreliable.id(), bytes);
+            }
+        }
+    }
+    state.history.push_back((tick, current));
+    while state.history.len() > SNAPSHOT_HISTORY_SIZE {
+        state.history.pop_front();
+    }
+}
+
+pub fn log_bandwidth(mut log: ResMut<BandwidthLog>, server: Res<RenetServer>, time: Res<Time>) {
+    log.timer.tick(time.delta());
+    if !log.timer.just_finished() {
+        return;
+    }
+    let seconds = log.timer.duration().as_secs_f32();
+    for client_id in server.clients_id() {
+        let snapshot_bytes = log.snapshot_bytes.remove(&client_id).unwrap_or_default();
+        if let Some(info) = server.network_info(client_id) {
+            info!(
+                "Client {}: snapshots {:.0} bytes/s, sending {:.1} kbps, rtt {:.0} ms, loss {:.1}%",
+                client_id,
+                snapshot_bytes as f32 / seconds,
+                info.sent_kbps,
+                info.rtt,
+                info.packet_loss * 100.0
+            );
+        }
+    }
+    log.snapshot_bytes.clear();
+}
+
+/// Floating numbers are cosmetic, so they go unreliably and a lost one stays lost.
+pub fn replicate_combat_text(
+    mut server: ResMut<RenetServer>,
+    mut damage_events: EventReader<DamageEvent>,
+    mut reward_events: EventReader<RewardEvent>,
+) {
+    let damage = damage_events.iter().map(|event| CombatText::Damage {
+        position: event.position.to_array(),
+        health: event.dealt.health,
+        shield: event.dealt.shield,
+        kind: event.kind,
+        crit: event.crit,
+    });
+    let rewards = reward_events.iter().map(|event| CombatText::Reward {
+        position: event.position.to_array(),
+        resource: event.resource,
+        amount: event.amount,
+    });
+    let texts: Vec<_> = damage.chain(rewards).collect();
+    for chunk in texts.chunks(COMBAT_TEXT_CHUNK_SIZE) {
+        let message = ServerMessage::CombatText(chunk.to_vec());
+        broadcast(&mut server, Channel::Unreliable, &message);
+    }
+}
+
+/// Sends everything that already exists to clients that just joined.
+pub fn sync_new_clients(
+    mut server: ResMut<RenetServer>,
+    mut joined: EventReader<PlayerJoined>,
+    q_replicated: Query<(
+        &NetworkId,
+        &Transform,
+        Option<&Building>,
+        Option<&Enemy>,
+        Option<&Projectile>,
+    )>,
+    q_consumers: Query<(&NetworkId, &PowerConsumer)>,
+    res: Res<PlayerResources>,
+    map: Res<MapSettings>,
+    mut state: ResMut<ReplicationState>,
+) {
+    for player in joined.iter() {
+        let mut spawns = Vec::new();
+        let mut upgrades = Vec::new();
+        for (&id, transform, building, enemy, projectile) in q_replicated.iter() {
+            if let Some(kind) = entity_kind(building, enemy, projectile) {
+                spawns.push((id, kind, transform.translation.to_array()));
+            }
+            if let Some(building) = building.filter(|building| !building.upgrades.is_empty()) {
+                upgrades.push((id, building.upgrades.clone()));
+            }
+        }
+        let priorities = q_consumers
+            .iter()
+            .filter(|(_, consumer)| consumer.priority != PowerPriority::Normal)
+            .map(|(&id, consumer)| (id, consumer.priority))
+            .collect();
+        let message = ServerMessage::Sync {
+            tick: state.tick,
+            map: *map,
+            spawns,
+            upgrades,
+            priorities,
+            resources: res.amounts(),
+            income: res.ledger.per_minute(),
+        };
+        send(&mut server, player.client_id, Channel::Block, &message);
+        state.held.insert(player.client_id, Vec::new());
+    }
+}
+
+pub fn forget_departed_clients(
+    mut left: EventReader<PlayerLeft>,
+    mut state: ResMut<ReplicationState>,
+) {
+    for player in left.iter() {
+        state.held.remove(&player.client_id);
+        state.acked.remove(&player.client_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{SocketAddr, UdpSocket},
+        thread,
+        time::{Duration, SystemTime},
+    };
+
+    use bevy_renet::renet::{ClientAuthentication, RenetClient};
+
+    use super::*;
+    use crate::{
+        combat_text::DamageKind,
+        enemy::{EnemyKind, SpawnEnemyRequest},
+        hp_bar::DamageDealt,
+        map::tile_to_world,
+        networking::{
+            connection_config,
+            protocol::{self, ClientMessage, ConnectInfo},
+            Players, PROTOCOL_ID,
+        },
+        testing::{headless_server, send},
+    };
+
+    const CLIENT_ID: u64 = 1;
+    const ENEMIES: i32 = 50;
+    // About two seconds, or 40 snapshots
+    const TICKS: usize = 120;
+    const FRAME: Duration = Duration::from_millis(16);
+
+    fn free_port() -> u16 {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.local_addr().unwrap().port()
+    }
+
+    fn connect(server_addr: SocketAddr) -> RenetClient {
+        connect_as(server_addr, CLIENT_ID, "tester")
+    }
+
+    fn connect_as(server_addr: SocketAddr, client_id: u64, username: &str) -> RenetClient {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let current_time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap();
+        let user_data = ConnectInfo::new(username.to_string(), client_id)
+            .to_user_data()
+            .unwrap();
+        let authentication = ClientAuthentication::Unsecure {
+            client_id,
+            protocol_id: PROTOCOL_ID,
+            server_addr,
+            user_data: Some(user_data),
+        };
+        RenetClient::new(
+            current_time,
+            socket,
+            client_id,
+            connection_config(),
+            authentication,
+        )
+        .unwrap()
+    }
+
+    fn reply(client: &mut RenetClient, channel: Channel, message: &ClientMessage) {
+        client.send_message(channel.id(), channel.encode(message).unwrap());
+    }
+
+    #[test]
+    fn acknowledged_snapshots_only_carry_changes() {
+        let server_addr = SocketAddr::from(([127, 0, 0, 1], free_port()));
+        let mut app = headless_server(server_addr);
+        // Without towers to walk to, enemies stand still and nothing changes after the
+        // first snapshot
+        for i in 0..ENEMIES {
+            let tile = IVec2::new(i % 10 - 5, i / 10 - 5);
+            send(
+                &mut app,
+                SpawnEnemyRequest {
+                    kind: EnemyKind::Melee,
+                    translation: tile_to_world(tile).extend(0.0),
+                },
+            );
+        }
+        let mut client = connect(server_addr);
+
+        let mut spawned = 0;
+        let mut full_snapshot = None;
+        let mut delta_snapshots = 0;
+        for _ in 0..TICKS {
+            app.update();
+            client.update(FRAME).unwrap();
+            for channel in Channel::ALL {
+                while let Some(bytes) = client.receive_message(channel.id()) {
+                    match protocol::decode::<ServerMessage>(&bytes).unwrap() {
+                        ServerMessage::Sync { spawns, .. } => {
+                            spawned += spawns.len();
+                            reply(&mut client, Channel::Reliable, &ClientMessage::SyncReceived);
+                        }
+                        ServerMessage::Spawn { .. } => spawned += 1,
+                        ServerMessage::Snapshot {
+                            tick,
+                            baseline,
+                            parts,
+                            entities,
+                            ..
+                        } => {
+                            assert_eq!(parts, 1);
+                            match baseline {
+                                None => {
+                                    assert_eq!(entities.len(), ENEMIES as usize);
+                                    full_snapshot.get_or_insert(bytes.len());
+                                }
+                                Some(_) => {
+                                    assert!(entities.is_empty());
+                                    delta_snapshots += 1;
+                                }
+                            }
+                            let ack = ClientMessage::SnapshotReceived(tick);
+                            reply(&mut client, Channel::Unreliable, &ack);
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            client.send_packets().unwrap();
+            thread::sleep(FRAME);
+        }
+
+        assert_eq!(spawned, ENEMIES as usize);
+        let full_snapshot = full_snapshot.expect("no full snapshot arrived");
+        assert!(delta_snapshots > 0);
+        let sent = app.world.resource::<BandwidthLog>().snapshot_bytes[&CLIENT_ID];
+        // Resending everything would cost a full snapshot every time
+        assert!(
+            sent < full_snapshot * 4,
+            "{} bytes of snapshots sent, a full one is {}",
+            sent,
+            full_snapshot
+        );
+    }
+
+    #[test]
+    fn combat_text_reaches_clients() {
+        let server_addr = SocketAddr::from(([127, 0, 0, 1], free_port()));
+        let mut app = headless_server(server_addr);
+        let mut client = connect(server_addr);
+
+        let mut received = None;
+        for _ in 0..TICKS {
+            send(
+                &mut app,
+                DamageEvent {
+                    position: Vec3::new(1.0, 2.0, 0.0),
+                    dealt: DamageDealt {
+                        health: 7.0,
+                        shield: 0.0,
+                    },
+                    kind: DamageKind::Projectile,
+                    crit: true,
+                },
+            );
+            app.update();
+            client.update(FRAME).unwrap();
+            for channel in Channel::ALL {
+                while let Some(bytes) = client.receive_message(channel.id()) {
+                    if let Ok(ServerMessage::CombatText(texts)) =
+                        protocol::decode::<ServerMessage>(&bytes)
+                    {
+                        received = texts.into_iter().next();
+                    }
+                }
+            }
+            if received.is_some() {
+                break;
+            }
+            client.send_packets().unwrap();
+            thread::sleep(FRAME);
+        }
+
+        match received.expect("no combat text arrived") {
+            CombatText::Damage {
+                position,
+                health,
+                crit,
+                ..
+            } => {
+                assert_eq!(position, [1.0, 2.0, 0.0]);
+                assert_eq!(health, 7.0);
+                assert!(crit);
+            }
+            other => panic!("received {:?}", other),
+        }
+    }
+
+    #[test]
+    fn taken_names_are_refused() {
+        let server_addr = SocketAddr::from(([127, 0, 0, 1], free_port()));
+        let mut app = headless_server(server_addr);
+        let mut first = connect_as(server_addr, CLIENT_ID, "tester");
+        let mut second = connect_as(server_addr, CLIENT_ID + 1, "tester");
+
+        let mut refused = None;
+        for _ in 0..TICKS {
+            app.update();
+            // The second only connects once the first has joined
+            first.update(FRAME).unwrap();
+            first.send_packets().unwrap();
+            if first.is_connected() {
+                // Fails once the refusal's disconnect arrives, what came before is kept
+                let _ = second.update(FRAME);
+                while let Some(bytes) = second.receive_message(Channel::Reliable.id()) {
+                    if let Ok(ServerMessage::Refused(reason)) =
+                        protocol::decode::<ServerMessage>(&bytes)
+                    {
+                        refused = Some(reason);
+                    }
+                }
+                second.send_packets().unwrap();
+            }
+            if refused.is_some() {
+                break;
+            }
+            thread::sleep(FRAME);
+        }
+
+        let reason = refused.expect("the second client was not refused");
+        assert!(reason.contains("already taken"), "{}", reason);
+        assert_eq!(
+            app.world.resource::<Players>().name(CLIENT_ID),
+            Some("tester")
+        );
+        assert_eq!(app.world.resource::<Players>().name(CLIENT_ID + 1), None);
+    }
+}
@@ -0,0 +1,148 @@
+//! Client side reassembly of snapshots. Parts are collected until a snapshot is complete
+//! and its deltas are resolved against the acknowledged snapshot they were built on.
+
+use std::collections::VecDeque;
+
+use bevy::utils::HashMap;
+
+use super::protocol::{EntityState, NetworkId};
+use crate::constants::*;
+
+/// Recently completed snapshots in full, and the parts of those still arriving.
+#[derive(Default)]
+pub struct SnapshotAssembler {
+    pending: HashMap<u32, PendingSnapshot>,
+    /// Full state at each completed tick, oldest first.
+    history: VecDeque<(u32, HashMap<NetworkId, EntityState>)>,
+}
+
+struct PendingSnapshot {
+    baseline: Option<u32>,
+    parts: Vec<Option<Vec<EntityState>>>,
+}
+
+impl SnapshotAssembler {
+    /// Adds one part of the snapshot at `tick`, returning the full state of every entity
+    /// once all parts are in. Snapshots older than the newest completed one are dropped, as
+    /// are parts whose numbering doesn't add up.
+    pub fn receive(
+        &mut self,
+        tick: u32,
+        baseline: Option<u32>,
+        part: u16,
+        parts: u16,
+        entities: Vec<EntityState>,
+    ) -> Option<Vec<EntityState>> {
+        if matches!(self.history.back(), Some(&(newest, _)) if tick <= newest) {
+            return None;
+        }
+        // Straight off the wire, so nothing is reserved for counts the server never sends
+        if parts == 0 || parts > MAX_SNAPSHOT_PARTS || part >= parts {
+            return None;
+        }
+        let pending = self.pending.entry(tick).or_insert_with(|| PendingSnapshot {
+            baseline,
+            parts: vec![None; parts as usize],
+        });
+        // Every part of a snapshot agrees on how many there are
+        if pending.parts.len() != parts as usize {
+            return None;
+        }
+        pending.parts[part as usize] = Some(entities);
+        if pending.parts.iter().any(Option::is_none) {
+            return None;
+        }
+        let pending = self.pending.remove(&tick)?;
+        // Whatever is still missing of older snapshots is superseded by this one
+        self.pending.retain(|&pending, _| pending > tick);
+
+        let mut states = match pending.baseline {
+            // The server only builds on acknowledged snapshots, which are kept here
+            Some(baseline) => self
+                .history
+                .iter()
+                .find(|(tick, _)| *tick == baseline)
+                .map(|(_, states)| states.clone())?,
+            None => HashMap::default(),
+        };
+        for delta in pending.parts.into_iter().flatten().flatten() {
+            states
+                .entry(delta.id)
+                .or_insert_with(|| EntityState {
+                    id: delta.id,
+                    ..Default::default()
+                })
+                .merge(&delta);
+        }
+        let full = states.values().cloned().collect();
+        self.history.push_back((tick, states));
+        while self.history.len() > SNAPSHOT_HISTORY_SIZE {
+            self.history.pop_front();
+        }
+        Some(full)
+    }
+
+    /// A despawned entity is left out of later snapshots without a trace, so it has to be
+    /// dropped from the baselines here.
+    pub fn forget(&mut self, id: NetworkId) {
+        for (_, states) in self.history.iter_mut() {
+            states.remove(&id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(id: u64) -> EntityState {
+        EntityState {
+            id: NetworkId(id),
+            health: Some((1.0, 1.0)),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn parts_are_reassembled() {
+        let mut assembler = SnapshotAssembler::default();
+        assert!(assembler.receive(1, None, 1, 2, vec![state(2)]).is_none());
+        let full = assembler.receive(1, None, 0, 2, vec![state(1)]).unwrap();
+        assert_eq!(full.len(), 2);
+        assert!(assembler.pending.is_empty());
+    }
+
+    #[test]
+    fn zero_parts_are_dropped() {
+        let mut assembler = SnapshotAssembler::default();
+        assert!(assembler.receive(1, None, 0, 0, vec![state(1)]).is_none());
+        assert!(assembler.pending.is_empty());
+    }
+
+    #[test]
+    fn part_out_of_range_is_dropped() {
+        let mut assembler = SnapshotAssembler::default();
+        assert!(assembler.receive(1, None, 2, 2, vec![state(1)]).is_none());
+        assert!(assembler.pending.is_empty());
+    }
+
+    #[test]
+    fn too_many_parts_are_dropped() {
+        let mut assembler = SnapshotAssembler::default();
+        let parts = MAX_SNAPSHOT_PARTS + 1;
+        assert!(assembler.receive(1, None, 0, parts, vec![]).is_none());
+        assert!(assembler.pending.is_empty());
+    }
+
+    #[test]
+    fn mismatched_part_count_is_dropped() {
+        let mut assembler = SnapshotAssembler::default();
+        assert!(assembler.receive(1, None, 0, 2, vec![state(1)]).is_none());
+        // Would complete a one part snapshot if taken at its word
+        assert!(assembler.receive(1, None, 0, 1, vec![state(1)]).is_none());
+        assert!(assembler.receive(1, None, 2, 3, vec![state(3)]).is_none());
+        assert_eq!(assembler.pending[&1].parts.len(), 2);
+        let full = assembler.receive(1, None, 1, 2, vec![state(2)]).unwrap();
+        assert_eq!(full.len(), 2);
+    }
+}
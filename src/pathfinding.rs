@@ -0,0 +1,59 @@
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
+};
+
+use bevy::prelude::*;
+
+use crate::map::{TileGrid, NEIGHBOURS};
+
+fn manhattan(a: IVec2, b: IVec2) -> i32 {
+    (a.x - b.x).abs() + (a.y - b.y).abs()
+}
+
+/// A* search over the tile grid. Returns the tiles to walk through, excluding `start` and
+/// ending with `goal`, or `None` when buildings block every route. The goal tile is allowed to
+/// be occupied, since that's usually the building being attacked.
+pub fn find_path(grid: &TileGrid, start: IVec2, goal: IVec2) -> Option<Vec<IVec2>> {
+    if !grid.contains(start) || !grid.contains(goal) {
+        return None;
+    }
+
+    let mut open = BinaryHeap::new();
+    let mut came_from: HashMap<IVec2, IVec2> = HashMap::new();
+    let mut cost: HashMap<IVec2, i32> = HashMap::new();
+    cost.insert(start, 0);
+    open.push(Reverse((manhattan(start, goal), start.x, start.y)));
+
+    while let Some(Reverse((_, x, y))) = open.pop() {
+        let tile = IVec2::new(x, y);
+        if tile == goal {
+            let mut path = vec![goal];
+            let mut current = goal;
+            while let Some(&previous) = came_from.get(&current) {
+                if previous == start {
+                    break;
+                }
+                path.push(previous);
+                current = previous;
+            }
+            path.reverse();
+            return Some(path);
+        }
+
+        let tile_cost = cost[&tile];
+        for offset in NEIGHBOURS {
+            let next = tile + offset;
+            if next != goal && !grid.is_passable(next) {
+                continue;
+            }
+            let next_cost = tile_cost + 1;
+            if cost.get(&next).map_or(true, |&c| next_cost < c) {
+                cost.insert(next, next_cost);
+                came_from.insert(next, tile);
+                open.push(Reverse((next_cost + manhattan(next, goal), next.x, next.y)));
+            }
+        }
+    }
+    None
+}
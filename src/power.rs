@@ -0,0 +1,374 @@
+use std::collections::HashMap;
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    building::{Building, UnderConstruction},
+    networking::run_if_authority,
+};
+
+pub struct PowerPlugin;
+
+impl Plugin for PowerPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<PowerGrid>()
+            .add_event::<SetPowerPriority>()
+            .add_system(set_power_priorities.with_run_criteria(run_if_authority))
+            .add_system(update_power_grid);
+    }
+}
+
+/// Generates power for the network the building is part of.
+#[derive(Component)]
+pub struct PowerSource {
+    pub output: f32,
+}
+
+/// Building that links power networks together. Anything within `radius` tiles of it, measured
+/// as the larger of the x and y distance, is connected.
+#[derive(Component)]
+pub struct PowerNode {
+    pub radius: i32,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Serialize, Deserialize)]
+pub enum PowerPriority {
+    Low,
+    Normal,
+    High,
+}
+
+impl PowerPriority {
+    pub const ALL: [PowerPriority; 3] = [
+        PowerPriority::Low,
+        PowerPriority::Normal,
+        PowerPriority::High,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            PowerPriority::Low => "Low",
+            PowerPriority::Normal => "Normal",
+            PowerPriority::High => "High",
+        }
+    }
+}
+
+/// Building that needs power to work. When a network is short on supply, higher priority
+/// consumers are served first.
+#[derive(Component)]
+pub struct PowerConsumer {
+    pub demand: f32,
+    pub priority: PowerPriority,
+    /// Fraction of `demand` currently supplied, from 0 (shut down) to 1 (fully powered).
+    pub satisfaction: f32,
+}
+
+impl PowerConsumer {
+    pub fn new(demand: f32) -> PowerConsumer {
+        PowerConsumer {
+            demand,
+            priority: PowerPriority::Normal,
+            satisfaction: 0.0,
+        }
+    }
+}
+
+pub struct SetPowerPriority {
+    pub building: Entity,
+    pub priority: PowerPriority,
+}
+
+#[derive(Default, Clone, Debug)]
+pub struct NetworkStatus {
+    pub supply: f32,
+    pub demand: f32,
+    pub nodes: usize,
+    pub consumers: usize,
+}
+
+/// Supply and demand of every connected power network, refreshed each frame.
+#[derive(Default)]
+pub struct PowerGrid {
+    pub networks: Vec<NetworkStatus>,
+}
+
+pub struct NodeInfo {
+    pub tile: IVec2,
+    pub radius: i32,
+    pub output: f32,
+}
+
+pub struct ConsumerInfo {
+    pub tile: IVec2,
+    pub demand: f32,
+    pub priority: PowerPriority,
+}
+
+pub struct PowerSolution {
+    pub networks: Vec<NetworkStatus>,
+    /// Satisfaction of each consumer, in the order they were passed in.
+    pub satisfaction: Vec<f32>,
+}
+
+fn in_range(a: IVec2, b: IVec2, radius: i32) -> bool {
+    (a - b).abs().max_element() <= radius
+}
+
+fn find(parent: &mut [usize], mut i: usize) -> usize {
+    while parent[i] != i {
+        parent[i] = parent[parent[i]];
+        i = parent[i];
+    }
+    i
+}
+
+/// Groups nodes into connected networks and splits each network's supply between its
+/// consumers by priority. Consumers of equal priority are served in the order given. A consumer
+/// reached by several networks draws from the one with the most supply left when it is served,
+/// the first one on a tie; one out of reach of every node gets no power at all.
+pub fn solve_power(nodes: &[NodeInfo], consumers: &[ConsumerInfo]) -> PowerSolution {
+    let mut parent: Vec<usize> = (0..nodes.len()).collect();
+    for i in 0..nodes.len() {
+        for j in i + 1..nodes.len() {
+            let radius = nodes[i].radius.max(nodes[j].radius);
+            if in_range(nodes[i].tile, nodes[j].tile, radius) {
+                let a = find(&mut parent, i);
+                let b = find(&mut parent, j);
+                parent[a] = b;
+            }
+        }
+    }
+
+    let mut networks: Vec<NetworkStatus> = Vec::new();
+    let mut network_of_root = HashMap::new();
+    let mut node_network = Vec::with_capacity(nodes.len());
+    for (i, node) in nodes.iter().enumerate() {
+        let root = find(&mut parent, i);
+        let network = *network_of_root.entry(root).or_insert_with(|| {
+            networks.push(NetworkStatus::default());
+            networks.len() - 1
+        });
+        networks[network].supply += node.output;
+        networks[network].nodes += 1;
+        node_network.push(network);
+    }
+
+    let consumer_networks: Vec<Vec<usize>> = consumers
+        .iter()
+        .map(|consumer| {
+            let mut reached: Vec<usize> = nodes
+                .iter()
+                .zip(&node_network)
+                .filter(|(node, _)| in_range(node.tile, consumer.tile, node.radius))
+                .map(|(_, &network)| network)
+                .collect();
+            reached.sort_unstable();
+            reached.dedup();
+            reached
+        })
+        .collect();
+
+    let mut order: Vec<usize> = (0..consumers.len()).collect();
+    order.sort_by(|&a, &b| consumers[b].priority.cmp(&consumers[a].priority));
+
+    let mut remaining: Vec<f32> = networks.iter().map(|n| n.supply).collect();
+    let mut satisfaction = vec![0.0; consumers.len()];
+    for i in order {
+        // Sorted by index, so only strictly more supply moves the choice away from the first
+        let network = match consumer_networks[i]
+            .iter()
+            .copied()
+            .reduce(|best, network| {
+                if remaining[network] > remaining[best] {
+                    network
+                } else {
+                    best
+                }
+            }) {
+            Some(x) => x,
+            None => continue,
+        };
+        let consumer = &consumers[i];
+        networks[network].demand += consumer.demand;
+        networks[network].consumers += 1;
+        if consumer.demand <= 0.0 {
+            satisfaction[i] = 1.0;
+            continue;
+        }
+        let supplied = remaining[network].min(consumer.demand);
+        remaining[network] -= supplied;
+        satisfaction[i] = supplied / consumer.demand;
+    }
+
+    PowerSolution {
+        networks,
+        satisfaction,
+    }
+}
+
+fn update_power_grid(
+    mut power_grid: ResMut<PowerGrid>,
+    q_nodes: Query<(&Building, &PowerNode, Option<&PowerSource>), Without<UnderConstruction>>,
+    mut q_consumers: Query<(&Building, &mut PowerConsumer), Without<UnderConstruction>>,
+) {
+    let nodes: Vec<NodeInfo> = q_nodes
+        .iter()
+        .map(|(building, node, source)| NodeInfo {
+            tile: building.tile,
+            radius: node.radius,
+            output: source.map_or(0.0, |s| s.output),
+        })
+        .collect();
+    let consumers: Vec<ConsumerInfo> = q_consumers
+        .iter()
+        .map(|(building, consumer)| ConsumerInfo {
+            tile: building.tile,
+            demand: consumer.demand,
+            priority: consumer.priority,
+        })
+        .collect();
+
+    let solution = solve_power(&nodes, &consumers);
+    for ((_, mut consumer), satisfaction) in q_consumers.iter_mut().zip(solution.satisfaction) {
+        consumer.satisfaction = satisfaction;
+    }
+    power_grid.networks = solution.networks;
+}
+
+fn set_power_priorities(
+    mut priority_events: EventReader<SetPowerPriority>,
+    mut q_consumers: Query<&mut PowerConsumer>,
+) {
+    for event in priority_events.iter() {
+        if let Ok(mut consumer) = q_consumers.get_mut(event.building) {
+            consumer.priority = event.priority;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::*;
+
+    fn generator(x: i32, y: i32) -> NodeInfo {
+        NodeInfo {
+            tile: IVec2::new(x, y),
+            radius: GENERATOR_RADIUS,
+            output: GENERATOR_OUTPUT,
+        }
+    }
+
+    fn pylon(x: i32, y: i32) -> NodeInfo {
+        NodeInfo {
+            tile: IVec2::new(x, y),
+            radius: PYLON_RADIUS,
+            output: 0.0,
+        }
+    }
+
+    fn consumer(x: i32, y: i32, priority: PowerPriority) -> ConsumerInfo {
+        ConsumerInfo {
+            tile: IVec2::new(x, y),
+            demand: TOWER_POWER_DEMAND,
+            priority,
+        }
+    }
+
+    #[test]
+    fn pylons_carry_power_to_distant_consumers() {
+        let nodes = [generator(0, 0), pylon(4, 0), pylon(8, 0)];
+        let consumers = [consumer(11, 0, PowerPriority::Normal)];
+        let solution = solve_power(&nodes, &consumers);
+        assert_eq!(solution.networks.len(), 1);
+        assert_eq!(solution.networks[0].nodes, 3);
+        assert_eq!(solution.networks[0].supply, GENERATOR_OUTPUT);
+        assert_eq!(solution.satisfaction, vec![1.0]);
+    }
+
+    #[test]
+    fn split_networks_share_nothing() {
+        let nodes = [generator(0, 0), pylon(20, 0)];
+        let consumers = [
+            consumer(1, 0, PowerPriority::Normal),
+            consumer(21, 0, PowerPriority::Normal),
+        ];
+        let solution = solve_power(&nodes, &consumers);
+        assert_eq!(solution.networks.len(), 2);
+        assert_eq!(solution.satisfaction, vec![1.0, 0.0]);
+    }
+
+    #[test]
+    fn generator_out_of_pylon_radius_is_not_connected() {
+        let nodes = [generator(0, 0), pylon(PYLON_RADIUS + 1, 0)];
+        let consumers = [consumer(PYLON_RADIUS + 2, 0, PowerPriority::Normal)];
+        let solution = solve_power(&nodes, &consumers);
+        assert_eq!(solution.networks.len(), 2);
+        assert_eq!(solution.satisfaction, vec![0.0]);
+    }
+
+    #[test]
+    fn consumer_between_networks_draws_from_the_fuller_one() {
+        // Two generators out of each other's reach, both reaching the consumers in between
+        let gap = GENERATOR_RADIUS + 1;
+        let nodes = [generator(0, 0), generator(gap, 0)];
+        let consumers = [
+            consumer(gap / 2, 0, PowerPriority::Normal),
+            consumer(gap / 2, 1, PowerPriority::Normal),
+        ];
+        let solution = solve_power(&nodes, &consumers);
+        assert_eq!(solution.networks.len(), 2);
+        // Equal supply goes to the first network, which then has less left than the second
+        assert_eq!(solution.networks[0].consumers, 1);
+        assert_eq!(solution.networks[1].consumers, 1);
+        assert_eq!(solution.satisfaction, vec![1.0, 1.0]);
+
+        // The same inputs always split the same way
+        let again = solve_power(&nodes, &consumers);
+        assert_eq!(again.networks[0].demand, solution.networks[0].demand);
+        assert_eq!(again.networks[1].demand, solution.networks[1].demand);
+    }
+
+    #[test]
+    fn consumer_out_of_reach_gets_nothing() {
+        let nodes = [generator(0, 0)];
+        let consumers = [consumer(GENERATOR_RADIUS + 1, 0, PowerPriority::High)];
+        let solution = solve_power(&nodes, &consumers);
+        assert_eq!(solution.networks[0].consumers, 0);
+        assert_eq!(solution.satisfaction, vec![0.0]);
+    }
+
+    #[test]
+    fn low_priority_is_shed_first() {
+        // Supply covers two full consumers and part of a third
+        let nodes = [generator(0, 0)];
+        let consumers = [
+            consumer(1, 0, PowerPriority::Low),
+            consumer(0, 1, PowerPriority::High),
+            consumer(-1, 0, PowerPriority::Normal),
+        ];
+        let solution = solve_power(&nodes, &consumers);
+        let leftover = GENERATOR_OUTPUT - 2.0 * TOWER_POWER_DEMAND;
+        assert_eq!(
+            solution.satisfaction,
+            vec![leftover / TOWER_POWER_DEMAND, 1.0, 1.0]
+        );
+        assert_eq!(solution.networks[0].demand, 3.0 * TOWER_POWER_DEMAND);
+    }
+
+    #[test]
+    fn equal_priority_is_served_in_order() {
+        let nodes = [generator(0, 0)];
+        let consumers = [
+            consumer(1, 0, PowerPriority::Normal),
+            consumer(0, 1, PowerPriority::Normal),
+            consumer(-1, 0, PowerPriority::Normal),
+        ];
+        let solution = solve_power(&nodes, &consumers);
+        assert_eq!(solution.satisfaction[0], 1.0);
+        assert_eq!(solution.satisfaction[1], 1.0);
+        assert!(solution.satisfaction[2] < 1.0);
+    }
+}
@@ -1,87 +1,300 @@
-use bevy::{prelude::*, sprite::collide_aabb::collide};
+use bevy::{ecs::system::SystemParam, prelude::*, sprite::collide_aabb::collide};
 use bevy_inspector_egui::Inspectable;
+use serde::{Deserialize, Serialize};
 
-use crate::{constants::*, enemy::Enemy, hp_bar::Health};
+use crate::{
+    assets::GameAssets,
+    combat_text::{DamageEvent, DamageKind},
+    constants::*,
+    hp_bar::{Health, Hit},
+    lifecycle::Despawning,
+    networking::{protocol::NetworkId, run_if_authority, NetworkEntities},
+};
 
 pub struct ProjectilePlugin;
 
+/// Side an entity fights for. Projectiles only hit entities of a different faction.
+#[derive(Component, Inspectable, Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum Faction {
+    Player,
+    Enemy,
+}
+
 #[derive(Component, Inspectable)]
 pub struct Projectile {
     damage: f32,
+    crit: bool,
     speed: f32,
     direction: Vec2,
     range: f32,
+    faction: Faction,
+}
+
+/// Hidden projectiles waiting to be fired again.
+#[derive(Default)]
+pub struct ProjectilePool {
+    free: Vec<Entity>,
+}
+
+/// Everything needed to fire a projectile, reusing a pooled one when possible.
+#[derive(SystemParam)]
+pub struct ProjectileSpawner<'w, 's> {
+    commands: Commands<'w, 's>,
+    pool: ResMut<'w, ProjectilePool>,
+    assets: Res<'w, GameAssets>,
 }
 
 impl Plugin for ProjectilePlugin {
     fn build(&self, app: &mut App) {
-        app.add_system(update_projectiles);
+        app.init_resource::<ProjectilePool>()
+            .add_system(update_projectiles.with_run_criteria(run_if_authority));
     }
 }
 
 fn update_projectiles(
     mut commands: Commands,
+    mut pool: ResMut<ProjectilePool>,
+    mut entities: ResMut<NetworkEntities>,
     mut q_projectiles: Query<
-        (Entity, &mut Transform, &mut Projectile),
-        (With<Projectile>, Without<Enemy>),
+        (
+            Entity,
+            &mut Transform,
+            &mut Projectile,
+            &mut Visibility,
+            Option<&NetworkId>,
+        ),
+        Without<Despawning>,
     >,
-    mut q_enemies: Query<(&mut Health, &mut Transform), With<Enemy>>,
+    mut q_targets: Query<
+        (&mut Health, &Transform, &Faction),
+        (Without<Projectile>, Without<Despawning>),
+    >,
+    mut damage_events: EventWriter<DamageEvent>,
     time: Res<Time>,
 ) {
-    for (entity, mut transform, mut projectile) in q_projectiles.iter_mut() {
+    for (entity, mut transform, mut projectile, mut visibility, id) in q_projectiles.iter_mut() {
+        // Pooled projectiles stay hidden until they are fired again
+        if !visibility.is_visible {
+            continue;
+        }
         let delta = (projectile.direction * projectile.speed * time.delta_seconds()).extend(0.0);
         transform.translation += delta;
 
-        let e = q_enemies.iter_mut().find(|x| {
-            collide(
-                x.1.translation,
-                Vec2::splat(TILE_SIZE * 0.7),
-                transform.translation,
-                Vec2::splat(TILE_SIZE * 0.2),
-            )
-            .is_some()
+        let target = q_targets.iter_mut().find(|x| {
+            *x.2 != projectile.faction
+                && x.0.current > 0.0
+                && collide(
+                    x.1.translation,
+                    Vec2::splat(TILE_SIZE * 0.7),
+                    transform.translation,
+                    Vec2::splat(TILE_SIZE * 0.2),
+                )
+                .is_some()
         });
 
-        if let Some((mut enemy_hp, _)) = e {
-            enemy_hp.current -= projectile.damage;
-            commands.entity(entity).despawn_recursive();
+        if let Some((mut target_hp, target_transform, _)) = target {
+            damage_events.send(DamageEvent {
+                position: target_transform.translation,
+                dealt: target_hp.damage(projectile.damage),
+                kind: DamageKind::Projectile,
+                crit: projectile.crit,
+            });
+            recycle(
+                &mut commands,
+                &mut pool,
+                &mut entities,
+                entity,
+                id,
+                &mut visibility,
+            );
+            continue;
         }
 
         projectile.range -= delta.length();
         if projectile.range <= 0.0 {
-            commands.entity(entity).despawn_recursive();
+            recycle(
+                &mut commands,
+                &mut pool,
+                &mut entities,
+                entity,
+                id,
+                &mut visibility,
+            );
+        }
+    }
+}
+
+/// Hides a spent projectile for reuse, or despawns it once the pool is full. A pooled
+/// projectile gives up its network id right away, so a reuse is always replicated as new.
+fn recycle(
+    commands: &mut Commands,
+    pool: &mut ProjectilePool,
+    entities: &mut NetworkEntities,
+    entity: Entity,
+    id: Option<&NetworkId>,
+    visibility: &mut Visibility,
+) {
+    if pool.free.len() < PROJECTILE_POOL_LIMIT {
+        visibility.is_visible = false;
+        pool.free.push(entity);
+        if let Some(&id) = id {
+            commands.entity(entity).remove::<NetworkId>();
+            entities.release(id);
         }
+    } else {
+        commands.entity(entity).insert(Despawning);
     }
 }
 
 impl Projectile {
+    pub fn faction(&self) -> Faction {
+        self.faction
+    }
+}
+
+impl<'w, 's> ProjectileSpawner<'w, 's> {
     pub fn spawn(
-        commands: &mut Commands,
+        &mut self,
         translation: Vec3,
         direction: Vec2,
-        asset_server: &Res<AssetServer>,
-    ) {
-        let _proj = commands
+        hit: Hit,
+        range: f32,
+        faction: Faction,
+    ) -> Entity {
+        let color = match faction {
+            Faction::Player => COLOR_PLAYER_PROJECTILE,
+            Faction::Enemy => COLOR_ENEMY_PROJECTILE,
+        };
+        let sprite = Sprite {
+            color,
+            custom_size: Some(Vec2::splat(TILE_SIZE * 0.2)),
+            ..Default::default()
+        };
+        let transform = Transform {
+            translation,
+            ..Default::default()
+        };
+        let projectile = Projectile {
+            damage: hit.damage,
+            crit: hit.crit,
+            speed: 1.0,
+            direction,
+            range,
+            faction,
+        };
+
+        // Overwriting components in place keeps the entity in its archetype
+        if let Some(entity) = self.pool.free.pop() {
+            self.commands
+                .entity(entity)
+                .insert(sprite)
+                .insert(transform)
+                .insert(projectile)
+                .insert(Visibility { is_visible: true });
+            return entity;
+        }
+        self.commands
             .spawn_bundle(SpriteBundle {
-                sprite: Sprite {
-                    color: Color::rgb(0.2, 0.2, 0.8),
-                    custom_size: Some(Vec2::splat(TILE_SIZE * 0.2)),
-                    ..Default::default()
-                },
-                texture: asset_server.load("sprites/projectile.png"),
-                transform: Transform {
-                    translation,
-                    ..Default::default()
-                },
+                sprite,
+                texture: self.assets.projectile.clone(),
+                transform,
                 ..Default::default()
             })
-            .insert(Projectile {
-                damage: 20.0,
-                speed: 1.0,
-                direction,
-                range: 10.0 * TILE_SIZE,
-            })
+            .insert(projectile)
             .insert(Name::new("Projectile"))
-            .id();
+            .id()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{count_events, headless_app, run_system};
+
+    fn spawn_target(app: &mut App, health: f32) -> Entity {
+        app.world
+            .spawn()
+            .insert(Transform::default())
+            .insert(Health::new(health))
+            .insert(Faction::Enemy)
+            .id()
+    }
+
+    fn fire(app: &mut App, damage: f32) -> Entity {
+        run_system(app, move |mut projectiles: ProjectileSpawner| {
+            projectiles.spawn(
+                Vec3::ZERO,
+                Vec2::X,
+                Hit {
+                    damage,
+                    crit: false,
+                },
+                TILE_SIZE,
+                Faction::Player,
+            )
+        })
+    }
+
+    fn is_visible(app: &App, entity: Entity) -> bool {
+        app.world.get::<Visibility>(entity).unwrap().is_visible
+    }
+
+    #[test]
+    fn hits_damage_the_target_and_return_to_the_pool() {
+        let mut app = headless_app();
+        let target = spawn_target(&mut app, 100.0);
+        let projectile = fire(&mut app, 30.0);
+        app.update();
+
+        assert_eq!(count_events::<DamageEvent>(&app), 1);
+        assert_eq!(app.world.get::<Health>(target).unwrap().current, 70.0);
+        assert!(!is_visible(&app, projectile));
+        assert_eq!(
+            app.world.resource::<ProjectilePool>().free,
+            vec![projectile]
+        );
+    }
+
+    #[test]
+    fn pooled_projectiles_release_their_network_id() {
+        let mut app = headless_app();
+        spawn_target(&mut app, 100.0);
+        let projectile = fire(&mut app, 30.0);
+        let id = app
+            .world
+            .resource_mut::<NetworkEntities>()
+            .allocate(projectile);
+        app.world.entity_mut(projectile).insert(id);
+        app.update();
+
+        assert!(app.world.get::<NetworkId>(projectile).is_none());
+        assert!(app.world.resource::<NetworkEntities>().get(id).is_none());
+    }
+
+    #[test]
+    fn dead_targets_are_not_hit_again() {
+        let mut app = headless_app();
+        let target = spawn_target(&mut app, 50.0);
+        let first = fire(&mut app, 50.0);
+        let second = fire(&mut app, 50.0);
+        app.update();
+
+        assert_eq!(count_events::<DamageEvent>(&app), 1);
+        assert_eq!(app.world.get::<Health>(target).unwrap().current, 0.0);
+        // Whichever projectile came first was spent, the other flies on
+        assert_ne!(is_visible(&app, first), is_visible(&app, second));
+    }
+
+    #[test]
+    fn own_faction_is_not_hit() {
+        let mut app = headless_app();
+        let target = spawn_target(&mut app, 100.0);
+        app.world.entity_mut(target).insert(Faction::Player);
+        let projectile = fire(&mut app, 30.0);
+        app.update();
+
+        assert_eq!(count_events::<DamageEvent>(&app), 0);
+        assert_eq!(app.world.get::<Health>(target).unwrap().current, 100.0);
+        assert!(is_visible(&app, projectile));
     }
 }
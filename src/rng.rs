@@ -0,0 +1,52 @@
+use bevy::prelude::SystemLabel;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+use crate::constants::DEFAULT_SEED;
+
+/// The only source of randomness the simulation may use, so a run can be
+/// reproduced from its seed.
+pub struct GameRng {
+    seed: u64,
+    rng: ChaCha8Rng,
+}
+
+impl GameRng {
+    pub fn new(seed: u64) -> Self {
+        GameRng {
+            seed,
+            rng: ChaCha8Rng::seed_from_u64(seed),
+        }
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Returns true with the given probability.
+    pub fn chance(&mut self, probability: f32) -> bool {
+        probability > 0.0 && self.rng.gen::<f32>() < probability
+    }
+
+    /// Uniform multiplier in `1 - spread ..= 1 + spread`.
+    pub fn spread(&mut self, spread: f32) -> f32 {
+        if spread <= 0.0 {
+            return 1.0;
+        }
+        self.rng.gen_range(1.0 - spread..=1.0 + spread)
+    }
+}
+
+/// Systems that draw from `GameRng`. They run in this order every frame, otherwise the
+/// scheduler would decide who gets which roll and a seed wouldn't replay the same way.
+#[derive(SystemLabel, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum RngUser {
+    Towers,
+    Enemies,
+}
+
+impl Default for GameRng {
+    fn default() -> Self {
+        GameRng::new(DEFAULT_SEED)
+    }
+}
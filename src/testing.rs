@@ -0,0 +1,67 @@
+//! Headless apps for tests, running the simulation without a window.
+
+use std::net::SocketAddr;
+
+use bevy::{
+    ecs::{event::Events, system::System},
+    hierarchy::HierarchyPlugin,
+    prelude::*,
+    transform::TransformPlugin,
+};
+
+use crate::{
+    add_simulation,
+    constants::*,
+    map::MapSettings,
+    networking::{NetworkRole, NetworkingPlugin},
+};
+
+// Small enough to generate quickly, every test stays near the centre
+const TEST_MAP_SIZE: i32 = 20;
+
+pub fn headless_app() -> App {
+    app_with_role(
+        NetworkRole::Singleplayer,
+        SocketAddr::from(([127, 0, 0, 1], DEFAULT_PORT)),
+    )
+}
+
+/// A hosting server listening on `addr`, for clients driven by the test itself.
+pub fn headless_server(addr: SocketAddr) -> App {
+    app_with_role(NetworkRole::Server, addr)
+}
+
+fn app_with_role(role: NetworkRole, addr: SocketAddr) -> App {
+    let mut app = App::new();
+    app.add_plugins(MinimalPlugins)
+        .add_plugin(TransformPlugin)
+        .add_plugin(HierarchyPlugin);
+    add_simulation(
+        &mut app,
+        NetworkingPlugin::new(role, addr, DEFAULT_USERNAME.to_string()),
+        MapSettings::new(TEST_MAP_SIZE, DEFAULT_SEED),
+        DEFAULT_SEED,
+    );
+    app
+}
+
+/// Runs `system` once against the app's world, e.g. to spawn through a `SystemParam`.
+pub fn run_system<Out, Params>(app: &mut App, system: impl IntoSystem<(), Out, Params>) -> Out {
+    let mut system = IntoSystem::into_system(system);
+    system.initialize(&mut app.world);
+    let out = system.run((), &mut app.world);
+    system.apply_buffers(&mut app.world);
+    out
+}
+
+pub fn send<E: Send + Sync + 'static>(app: &mut App, event: E) {
+    app.world.resource_mut::<Events<E>>().send(event);
+}
+
+/// Events of type `E` sent during the last update.
+pub fn count_events<E: Send + Sync + 'static>(app: &App) -> usize {
+    app.world
+        .resource::<Events<E>>()
+        .iter_current_update_events()
+        .count()
+}
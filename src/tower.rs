@@ -4,45 +4,57 @@ use bevy::{math::Vec3Swizzles, prelude::*};
 use bevy_inspector_egui::Inspectable;
 
 use crate::{
+    assets::GameAssets,
+    building::UnderConstruction,
     constants::*,
     enemy::Enemy,
-    hp_bar::{create_hp_bar, Health},
-    projectile::Projectile,
+    hp_bar::DamageRoll,
+    lifecycle::Despawning,
+    networking::run_if_authority,
+    power::PowerConsumer,
+    projectile::{Faction, ProjectileSpawner},
+    rng::{GameRng, RngUser},
 };
 
 pub struct TowerPlugin;
 
 #[derive(Component, Inspectable)]
-pub struct Tower;
+pub struct Tower {
+    pub damage: f32,
+    pub range: f32,
+    pub roll: DamageRoll,
+}
 
 #[derive(Component, Default, Reflect)]
 #[reflect(Component)]
 pub struct AttackTimer {
-    timer: Timer,
+    pub timer: Timer,
 }
 
 impl Plugin for TowerPlugin {
     fn build(&self, app: &mut App) {
-        app.add_system(update_towers);
+        app.add_system(
+            update_towers
+                .with_run_criteria(run_if_authority)
+                .label(RngUser::Towers),
+        );
     }
 }
 
 fn update_towers(
-    mut commands: Commands,
+    mut projectiles: ProjectileSpawner,
     mut q_towers: Query<
-        (Entity, &mut Health, &mut Transform, &mut AttackTimer),
-        (With<Tower>, Without<Enemy>),
+        (&Tower, &PowerConsumer, &mut Transform, &mut AttackTimer),
+        (Without<Enemy>, Without<UnderConstruction>),
+    >,
+    q_enemies: Query<
+        (Entity, &mut Enemy, &mut Transform),
+        (Without<Tower>, With<Enemy>, Without<Despawning>),
     >,
-    q_enemies: Query<(Entity, &mut Enemy, &mut Transform), (Without<Tower>, With<Enemy>)>,
+    mut rng: ResMut<GameRng>,
     time: Res<Time>,
-    asset_server: Res<AssetServer>,
 ) {
-    for (entity, health, mut transform, mut attack_timer) in q_towers.iter_mut() {
-        if health.current < 0.0 {
-            commands.entity(entity).despawn_recursive();
-            continue;
-        }
-
+    for (tower, power, mut transform, mut attack_timer) in q_towers.iter_mut() {
         let pos: Vec2 = transform.translation.truncate();
         let mut target: Vec2 = Vec2::new(0.0, 0.0);
 
@@ -65,13 +77,17 @@ fn update_towers(
         }
 
         if let Some(_) = closest_enemy {
-            attack_timer.timer.tick(time.delta());
+            // Underpowered towers reload slower, unpowered ones don't fire at all.
+            attack_timer
+                .timer
+                .tick(time.delta().mul_f32(power.satisfaction));
             if attack_timer.timer.just_finished() {
-                Projectile::spawn(
-                    &mut commands,
+                projectiles.spawn(
                     transform.translation.xy().extend(PROJECTILE_LAYER),
                     transform.rotation.mul_vec3(Vec3::Y).xy(),
-                    &asset_server,
+                    tower.roll.roll(tower.damage, &mut rng),
+                    tower.range,
+                    Faction::Player,
                 );
             }
         }
@@ -79,38 +95,36 @@ fn update_towers(
 }
 
 impl Tower {
-    pub fn create_tower(mut commands: Commands, translation: Vec3, asset_server: Res<AssetServer>) {
+    pub fn create_tower(commands: &mut Commands, translation: Vec3, assets: &GameAssets) -> Entity {
         let trans = Transform {
             translation,
             ..Default::default()
         };
-        let tower = commands
+        commands
             .spawn_bundle(SpriteBundle {
                 sprite: Sprite {
                     color: COLOR_TOWER,
                     custom_size: Some(Vec2::splat(TILE_SIZE * 0.7)),
                     ..Default::default()
                 },
-                texture: asset_server.load("sprites/tower.png"),
+                texture: assets.tower.clone(),
                 transform: trans,
                 ..Default::default()
             })
-            .insert(Tower)
+            .insert(Tower {
+                damage: 20.0,
+                range: 8.0 * TILE_SIZE,
+                roll: DamageRoll {
+                    crit_chance: TOWER_CRIT_CHANCE,
+                    crit_multiplier: TOWER_CRIT_MULTIPLIER,
+                    variance: TOWER_DAMAGE_VARIANCE,
+                },
+            })
             .insert(Name::new("Tower"))
             .insert(AttackTimer {
                 timer: Timer::from_seconds(1.0, true),
             })
-            .insert(Health {
-                current: 100.0,
-                max: 100.0,
-            })
-            .id();
-        let hp_bar = create_hp_bar(
-            &mut commands,
-            Vec2::new(0.0, TILE_SIZE * 0.5),
-            Vec2::new(TILE_SIZE * 0.85, TILE_SIZE * 0.1),
-            tower,
-        );
-        commands.entity(tower).add_child(hp_bar);
+            .insert(PowerConsumer::new(TOWER_POWER_DEMAND))
+            .id()
     }
 }
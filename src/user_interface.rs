@@ -2,6 +2,7 @@ use std::{collections::HashMap, fmt::format};
 
 use bevy::{
     diagnostic::{Diagnostics, FrameTimeDiagnosticsPlugin},
+    ecs::system::SystemParam,
     input::mouse::MouseWheel,
     prelude::*,
     render::camera::{RenderTarget, ScalingMode},
@@ -12,7 +13,20 @@ use bevy_egui::{
 };
 use bevy_inspector_egui::egui::{epaint::image, Color32};
 
-use crate::{building::Miner, constants::*, enemy::Enemy, tower::Tower, AppState, PlayerResources};
+use crate::{
+    building::{
+        repair_cost, ActionRejected, BuildRequest, Building, BuildingKind, Extractor, RefundRate,
+        RepairRequest, Repairing, SellRequest, UnderConstruction, UpgradeRequest,
+    },
+    constants::*,
+    economy::{format_amounts, Amount, PlayerResources, ResourceKind},
+    enemy::{EnemyKind, SpawnEnemyRequest},
+    hp_bar::Health,
+    map::{world_to_tile, TileGrid},
+    power::{PowerConsumer, PowerGrid, PowerPriority, SetPowerPriority},
+    tower::Tower,
+    AppState,
+};
 #[derive(Component)]
 struct FpsText;
 pub struct UserInterfacePlugin;
@@ -28,8 +42,8 @@ struct UiState {
 #[derive(Eq, Hash, PartialEq, Clone, Copy)]
 pub enum Icons {
     Enemy,
-    Tower,
-    Miner,
+    RangedEnemy,
+    Building(BuildingKind),
 }
 struct Icon {
     _handle: Handle<Image>,
@@ -59,22 +73,44 @@ impl Icon {
 #[derive(Default)]
 pub struct RightPanelWidth(pub f32);
 
+/// World position under the mouse, `None` when it's outside the window or over the side panel.
+#[derive(Default)]
+pub struct CursorWorldPos(pub Option<Vec2>);
+
+/// Building shown in the inspection panel.
+#[derive(Default)]
+pub struct SelectedBuilding(pub Option<Entity>);
+
+/// Actions the inspection panel can perform on the selected building.
+#[derive(SystemParam)]
+struct BuildingActions<'w, 's> {
+    upgrade: EventWriter<'w, 's, UpgradeRequest>,
+    sell: EventWriter<'w, 's, SellRequest>,
+    repair: EventWriter<'w, 's, RepairRequest>,
+    power_priority: EventWriter<'w, 's, SetPowerPriority>,
+}
+
 impl Plugin for UserInterfacePlugin {
     fn build(&self, app: &mut App) {
         app.add_plugin(FrameTimeDiagnosticsPlugin::default())
             .init_resource::<UiState>()
             .init_resource::<Option<Icons>>()
             .init_resource::<RightPanelWidth>()
+            .init_resource::<CursorWorldPos>()
+            .init_resource::<SelectedBuilding>()
             .add_startup_system(ui_setup)
             .add_startup_system(spawn_camera)
             .add_startup_system(spawn_cursor_marker)
             // .add_system(ui_update)
             .add_system(camera_follow)
+            .add_system_to_stage(CoreStage::PreUpdate, update_cursor)
             .add_plugin(EguiPlugin)
             // Systems that create Egui widgets should be run during the `CoreStage::Update` stage,
             // or after the `EguiSystem::BeginFrame` system (which belongs to the `CoreStage::PreUpdate` stage).
             .add_system(ui_example)
-            .add_system_set(SystemSet::on_update(AppState::Building).with_system(cursor_position));
+            .add_system(show_rejections)
+            .add_system_set(SystemSet::on_update(AppState::Building).with_system(cursor_position))
+            .add_system_set(SystemSet::on_update(AppState::Main).with_system(select_building));
     }
 }
 fn ui_example(
@@ -85,42 +121,222 @@ fn ui_example(
     mut selection: ResMut<Option<Icons>>,
     mut panel_width: ResMut<RightPanelWidth>,
     player_resources: Res<PlayerResources>,
+    selected: Res<SelectedBuilding>,
+    q_buildings: Query<(
+        &Building,
+        &Health,
+        Option<&Tower>,
+        Option<&Extractor>,
+        Option<&Repairing>,
+        Option<&UnderConstruction>,
+        Option<&PowerConsumer>,
+    )>,
+    mut actions: BuildingActions,
+    refund_rate: Res<RefundRate>,
+    power_grid: Res<PowerGrid>,
 ) {
     panel_width.0 = egui::SidePanel::right("right_panel")
         .resizable(true)
         .show(egui_context.ctx_mut(), |ui| {
             // Shorter version:
-            for (key, icon) in ui_state.icons.iter_mut() {
-                if let Some(x) = *selection {
-                    if x == *key {
-                        icon.clicked = true;
+            ui.horizontal_wrapped(|ui| {
+                for (key, icon) in ui_state.icons.iter_mut() {
+                    if let Some(x) = *selection {
+                        if x == *key {
+                            icon.clicked = true;
+                        } else {
+                            icon.clicked = false;
+                        }
                     } else {
                         icon.clicked = false;
                     }
-                } else {
-                    icon.clicked = false;
+                    let image_button = ImageButton::new(icon.texture_id, [50.0, 50.0])
+                        .selected(icon.clicked)
+                        .tint(Color32::from_rgb(
+                            (icon.tint.r() * 255.0) as u8,
+                            (icon.tint.g() * 255.0) as u8,
+                            (icon.tint.b() * 255.0) as u8,
+                        ));
+                    let mut response = ui.add(image_button);
+                    if let Icons::Building(kind) = key {
+                        let def = kind.def();
+                        let mut text = format!("{} ({})", def.name, format_amounts(def.cost));
+                        if let Some(deposit) = def.deposit {
+                            text += &format!("\nMust be placed on {}", deposit.name());
+                        }
+                        response = response.on_hover_text(text);
+                    }
+                    if response.clicked() {
+                        icon.clicked = !icon.clicked;
+                        if icon.clicked {
+                            *selection = Some(*key);
+
+                            _ = app_state.set(AppState::Building);
+                        } else {
+                            *selection = None;
+                            _ = app_state.set(AppState::Main);
+                        }
+                    }
                 }
-                let image_button = ImageButton::new(icon.texture_id, [50.0, 50.0])
-                    .selected(icon.clicked)
-                    .tint(Color32::from_rgb(
-                        (icon.tint.r() * 255.0) as u8,
-                        (icon.tint.g() * 255.0) as u8,
-                        (icon.tint.b() * 255.0) as u8,
-                    ));
-                if ui.add(image_button).clicked() {
-                    icon.clicked = !icon.clicked;
-                    if icon.clicked {
-                        *selection = Some(*key);
+            });
+
+            for kind in ResourceKind::ALL {
+                let name = kind.name();
+                ui.label(format!(
+                    "{}{}: {}",
+                    name[..1].to_uppercase(),
+                    &name[1..],
+                    player_resources.get(kind)
+                ));
+            }
+
+            ui.collapsing("Income per minute", |ui| {
+                let income = player_resources.ledger.per_minute();
+                if income.is_empty() {
+                    ui.label("Nothing yet");
+                }
+                for (source, kind, amount) in income {
+                    let text = format!(
+                        "{}: {:+.1} {}",
+                        source.label(),
+                        amount.as_f32(),
+                        kind.name()
+                    );
+                    if amount < Amount::ZERO {
+                        ui.colored_label(Color32::RED, text);
+                    } else {
+                        ui.label(text);
+                    }
+                }
+            });
 
-                        _ = app_state.set(AppState::Building);
+            if !power_grid.networks.is_empty() {
+                ui.separator();
+                ui.label("Power networks (supply/demand):");
+                for (i, network) in power_grid.networks.iter().enumerate() {
+                    let text = format!("#{}: {:.0}/{:.0}", i + 1, network.supply, network.demand);
+                    if network.supply < network.demand {
+                        ui.colored_label(Color32::RED, text);
                     } else {
-                        *selection = None;
-                        _ = app_state.set(AppState::Main);
+                        ui.label(text);
                     }
                 }
             }
 
-            ui.label(format!("Gold: {:.0}", player_resources.gold));
+            if let Some(Ok((building, health, tower, extractor, repairing, construction, power))) =
+                selected.0.map(|entity| q_buildings.get(entity))
+            {
+                let def = building.kind.def();
+                ui.separator();
+                ui.heading(format!(
+                    "{} (tier {}/{})",
+                    def.name, building.tier, def.max_tier
+                ));
+                ui.label(format!("Health: {:.0}/{:.0}", health.current, health.max));
+                if health.max_shield > 0.0 {
+                    ui.label(format!(
+                        "Shield: {:.0}/{:.0}",
+                        health.shield, health.max_shield
+                    ));
+                }
+                if health.regen > 0.0 {
+                    ui.label(format!("Regeneration: {:.1}/s", health.regen));
+                }
+                if health.is_invulnerable() {
+                    ui.label("Invulnerable");
+                }
+                if let Some(construction) = construction {
+                    ui.label(format!(
+                        "Under construction: {:.0}%",
+                        construction.timer.percent() * 100.0
+                    ));
+                }
+                if let Some(tower) = tower {
+                    ui.label(format!("Damage: {:.1}", tower.damage));
+                    if tower.roll.crit_chance > 0.0 {
+                        ui.label(format!(
+                            "Crit: {:.0}% for x{:.1}",
+                            tower.roll.crit_chance * 100.0,
+                            tower.roll.crit_multiplier
+                        ));
+                    }
+                    ui.label(format!("Range: {:.1} tiles", tower.range / TILE_SIZE));
+                }
+                if let Some(power) = power {
+                    ui.label(format!("Power: {:.0}%", power.satisfaction * 100.0));
+                    ui.horizontal(|ui| {
+                        ui.label("Priority:");
+                        for priority in PowerPriority::ALL {
+                            if ui
+                                .selectable_label(power.priority == priority, priority.name())
+                                .clicked()
+                            {
+                                actions.power_priority.send(SetPowerPriority {
+                                    building: selected.0.unwrap(),
+                                    priority,
+                                });
+                            }
+                        }
+                    });
+                }
+                if let Some(extractor) = extractor {
+                    ui.label(format!(
+                        "Yield: {:.1} {}/s",
+                        extractor.amount.as_f32(),
+                        extractor.resource.name()
+                    ));
+                }
+                if let Some(recipe) = &def.recipe {
+                    ui.label(format!(
+                        "Turns {} into {} every {:.0}s",
+                        format_amounts(recipe.inputs),
+                        format_amounts(recipe.outputs),
+                        recipe.time
+                    ));
+                }
+                for (i, upgrade) in def.upgrades.iter().enumerate() {
+                    let cost = upgrade.cost_at(building.tier);
+                    let enabled = construction.is_none()
+                        && building.tier < def.max_tier
+                        && player_resources.get(ResourceKind::Gold) >= cost;
+                    let label = format!(
+                        "{} +{:.0}% ({} gold)",
+                        upgrade.name,
+                        (upgrade.factor - 1.0) * 100.0,
+                        cost
+                    );
+                    if ui.add_enabled(enabled, egui::Button::new(label)).clicked() {
+                        actions.upgrade.send(UpgradeRequest {
+                            building: selected.0.unwrap(),
+                            upgrade: i,
+                            player: None,
+                        });
+                    }
+                }
+                if repairing.is_some() {
+                    ui.label("Repairing...");
+                } else {
+                    let damaged = construction.is_none() && health.current < health.max;
+                    let label = format!("Repair (~{} gold) [R]", repair_cost(health));
+                    if ui.add_enabled(damaged, egui::Button::new(label)).clicked() {
+                        actions.repair.send(RepairRequest {
+                            building: selected.0.unwrap(),
+                            player: None,
+                        });
+                    }
+                }
+                let refund = building.refund(&refund_rate);
+                if ui
+                    .button(format!("Sell (+{}) [Del]", format_amounts(&refund)))
+                    .clicked()
+                {
+                    actions.sell.send(SellRequest {
+                        building: selected.0.unwrap(),
+                        player: None,
+                    });
+                }
+                ui.separator();
+            }
             if ui.button("Click me").clicked() {
                 // take some action here
             };
@@ -191,38 +407,36 @@ fn ui_setup(
         ),
     );
     ui_state.icons.insert(
-        Icons::Tower,
+        Icons::RangedEnemy,
         Icon::new(
-            "sprites/tower.png",
-            &asset_server,
-            &mut egui_context,
-            COLOR_TOWER,
-        ),
-    );
-    ui_state.icons.insert(
-        Icons::Miner,
-        Icon::new(
-            "sprites/tower.png",
+            "sprites/enemy.png",
             &asset_server,
             &mut egui_context,
-            COLOR_MINER,
+            COLOR_RANGED_ENEMY,
         ),
     );
+    for kind in BuildingKind::ALL {
+        let path = match kind {
+            BuildingKind::Wall => "sprites/projectile.png",
+            _ => "sprites/tower.png",
+        };
+        ui_state.icons.insert(
+            Icons::Building(kind),
+            Icon::new(path, &asset_server, &mut egui_context, kind.def().color),
+        );
+    }
 }
 
-fn cursor_position(
-    commands: Commands,
+fn update_cursor(
     // need to get window dimensions
     wnds: Res<Windows>,
     // query to get camera transform
     q_camera: Query<(&Camera, &GlobalTransform), With<MainCamera>>,
-    mut q_marker: Query<&mut Transform, With<CursorMarker>>,
-    buttons: Res<Input<MouseButton>>,
-    asset_server: Res<AssetServer>,
-    mut selection: ResMut<Option<Icons>>,
-    mut app_state: ResMut<State<AppState>>,
     panel_width: Res<RightPanelWidth>,
+    mut cursor: ResMut<CursorWorldPos>,
 ) {
+    cursor.0 = None;
+
     // get the camera info and transform
     // assuming there is exactly one main camera entity, so query::single() is OK
     let (camera, camera_transform) = q_camera.single();
@@ -251,19 +465,42 @@ fn cursor_position(
 
         // use it to convert ndc to world-space coordinates
         let world_pos = ndc_to_world.project_point3(ndc.extend(-1.0));
+        cursor.0 = Some(world_pos.truncate());
+    }
+}
 
-        let tile_x = ((world_pos.x + TILE_SIZE / 2.0) / TILE_SIZE).floor();
-        let tile_y = ((world_pos.y + TILE_SIZE / 2.0) / TILE_SIZE).floor();
+fn cursor_position(
+    cursor: Res<CursorWorldPos>,
+    mut q_marker: Query<&mut Transform, With<CursorMarker>>,
+    buttons: Res<Input<MouseButton>>,
+    mut selection: ResMut<Option<Icons>>,
+    mut app_state: ResMut<State<AppState>>,
+    mut build_events: EventWriter<BuildRequest>,
+    mut enemy_events: EventWriter<SpawnEnemyRequest>,
+) {
+    if let Some(world_pos) = cursor.0 {
+        let tile = world_to_tile(world_pos);
 
         let mut marker = q_marker.single_mut();
-        marker.translation.x = tile_x * TILE_SIZE;
-        marker.translation.y = tile_y * TILE_SIZE;
+        marker.translation.x = tile.x as f32 * TILE_SIZE;
+        marker.translation.y = tile.y as f32 * TILE_SIZE;
         if buttons.just_pressed(MouseButton::Left) {
             if let Some(x) = *selection {
+                let translation = world_pos.extend(0.0);
                 match x {
-                    Icons::Enemy => Enemy::new(commands, world_pos, asset_server),
-                    Icons::Tower => Tower::create_tower(commands, marker.translation, asset_server),
-                    Icons::Miner => Miner::new(commands, marker.translation, asset_server),
+                    Icons::Enemy => enemy_events.send(SpawnEnemyRequest {
+                        kind: EnemyKind::Melee,
+                        translation,
+                    }),
+                    Icons::RangedEnemy => enemy_events.send(SpawnEnemyRequest {
+                        kind: EnemyKind::Ranged,
+                        translation,
+                    }),
+                    Icons::Building(kind) => build_events.send(BuildRequest {
+                        kind,
+                        tile,
+                        player: None,
+                    }),
                 }
             }
         } else if buttons.just_pressed(MouseButton::Right) {
@@ -273,8 +510,50 @@ fn cursor_position(
     }
 }
 
+fn show_rejections(mut rejections: EventReader<ActionRejected>) {
+    for rejection in rejections.iter() {
+        if rejection.player.is_none() {
+            warn!("{}", rejection.reason);
+        }
+    }
+}
+
+fn select_building(
+    cursor: Res<CursorWorldPos>,
+    buttons: Res<Input<MouseButton>>,
+    keyboard: Res<Input<KeyCode>>,
+    grid: Res<TileGrid>,
+    mut selected: ResMut<SelectedBuilding>,
+    mut sell_events: EventWriter<SellRequest>,
+    mut repair_events: EventWriter<RepairRequest>,
+) {
+    if keyboard.just_pressed(KeyCode::Delete) {
+        if let Some(building) = selected.0.take() {
+            sell_events.send(SellRequest {
+                building,
+                player: None,
+            });
+        }
+    }
+    if keyboard.just_pressed(KeyCode::R) {
+        if let Some(building) = selected.0 {
+            repair_events.send(RepairRequest {
+                building,
+                player: None,
+            });
+        }
+    }
+    if let Some(world_pos) = cursor.0 {
+        if buttons.just_pressed(MouseButton::Left) {
+            selected.0 = grid.occupant(world_to_tile(world_pos));
+        } else if buttons.just_pressed(MouseButton::Right) {
+            selected.0 = None;
+        }
+    }
+}
+
 #[derive(Component)]
-struct MainCamera;
+pub struct MainCamera;
 fn spawn_camera(mut commands: Commands) {
     let mut camera = Camera2dBundle::default();
 